@@ -0,0 +1,695 @@
+use crate::error::{PipeError, Result};
+use crate::expand::NoMatch;
+use crate::input::{self, Feeder};
+use crate::output::{PipelineOutput, StageOutput};
+use crate::policy::SuccessPolicy;
+use crate::redirect::{FileMode, StageStdout, Stderr, Target};
+use crate::{command_line, spawn_failed, Pipe, Prepared};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::ffi::{OsStr, OsString};
+use std::io::{PipeReader, Read};
+use std::mem;
+use std::panic;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{self as std_process, Command};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::unix::pipe::Receiver;
+use tokio::process::{self, Child, ChildStderr};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+/// A `Pipe` that runs on tokio, so that waiting on its commands
+/// doesn't block the thread it's awaited from.
+///
+/// It's put together exactly like a `Pipe`, with the same methods
+/// for chaining commands and choosing where they read and write.
+/// Every command is spawned with tokio, so it has to be run from
+/// within a tokio runtime.
+///
+/// ```rust
+/// use pipers::AsyncPipe;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let out = AsyncPipe::new("echo hello")
+///     .then("tr a-z A-Z")
+///     .output()
+///     .await
+///     .expect("Commands did not pipe");
+/// assert_eq!(b"HELLO\n", &out.stdout[..]);
+/// # });
+/// ```
+pub struct AsyncPipe {
+    pipe: Pipe,
+}
+
+impl AsyncPipe {
+    /// Creates a new `AsyncPipe` from a command, just like `Pipe::new`.
+    pub fn new(command: &str) -> AsyncPipe {
+        AsyncPipe {
+            pipe: Pipe::new(command),
+        }
+    }
+
+    /// Creates a new `AsyncPipe` from a program and its arguments,
+    /// just like `Pipe::new_args`.
+    pub fn new_args<P, I, S>(program: P, args: I) -> AsyncPipe
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        AsyncPipe {
+            pipe: Pipe::new_args(program, args),
+        }
+    }
+
+    /// Creates a new `AsyncPipe` from a `Command`, just like
+    /// `Pipe::from_command`.
+    pub fn from_command(command: Command) -> AsyncPipe {
+        AsyncPipe {
+            pipe: Pipe::from_command(command),
+        }
+    }
+
+    /// Creates a new `AsyncPipe` from a whole pipeline, just like
+    /// `Pipe::parse`.
+    pub fn parse(pipeline: &str) -> AsyncPipe {
+        AsyncPipe {
+            pipe: Pipe::parse(pipeline),
+        }
+    }
+
+    /// Change the `Pipe` underneath.
+    fn map<F: FnOnce(Pipe) -> Pipe>(self, f: F) -> AsyncPipe {
+        AsyncPipe { pipe: f(self.pipe) }
+    }
+
+    /// See `Pipe::then`.
+    pub fn then(self, command: &str) -> AsyncPipe {
+        self.map(|pipe| pipe.then(command))
+    }
+
+    /// See `Pipe::then_args`.
+    pub fn then_args<P, I, S>(self, program: P, args: I) -> AsyncPipe
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.map(|pipe| pipe.then_args(program, args))
+    }
+
+    /// See `Pipe::then_command`.
+    pub fn then_command(self, command: Command) -> AsyncPipe {
+        self.map(|pipe| pipe.then_command(command))
+    }
+
+    /// See `Pipe::policy`.
+    pub fn policy(self, policy: SuccessPolicy) -> AsyncPipe {
+        self.map(|pipe| pipe.policy(policy))
+    }
+
+    /// See `Pipe::with_input`.
+    pub fn with_input<B: Into<Vec<u8>>>(self, bytes: B) -> AsyncPipe {
+        self.map(|pipe| pipe.with_input(bytes))
+    }
+
+    /// See `Pipe::stdin_file`.
+    pub fn stdin_file<P: AsRef<Path>>(self, path: P) -> AsyncPipe {
+        self.map(|pipe| pipe.stdin_file(path))
+    }
+
+    /// See `Pipe::stdin_reader`. The reader is still read from a
+    /// separate thread, since reading from it may block.
+    pub fn stdin_reader<R: Read + Send + 'static>(self, reader: R) -> AsyncPipe {
+        self.map(|pipe| pipe.stdin_reader(reader))
+    }
+
+    /// See `Pipe::capture_stderr`.
+    pub fn capture_stderr(self) -> AsyncPipe {
+        self.map(Pipe::capture_stderr)
+    }
+
+    /// See `Pipe::stderr`.
+    pub fn stderr(self, stderr: Stderr) -> AsyncPipe {
+        self.map(|pipe| pipe.stderr(stderr))
+    }
+
+    /// See `Pipe::stderr_limit`.
+    pub fn stderr_limit(self, limit: usize) -> AsyncPipe {
+        self.map(|pipe| pipe.stderr_limit(limit))
+    }
+
+    /// See `Pipe::expand_env`.
+    pub fn expand_env(self) -> AsyncPipe {
+        self.map(Pipe::expand_env)
+    }
+
+    /// See `Pipe::expand_with`.
+    pub fn expand_with<I, K, V>(self, variables: I) -> AsyncPipe
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<OsString>,
+    {
+        self.map(|pipe| pipe.expand_with(variables))
+    }
+
+    /// See `Pipe::glob`.
+    pub fn glob(self, no_match: NoMatch) -> AsyncPipe {
+        self.map(|pipe| pipe.glob(no_match))
+    }
+
+    /// See `Pipe::current_dir`.
+    pub fn current_dir<P: AsRef<Path>>(self, dir: P) -> AsyncPipe {
+        self.map(|pipe| pipe.current_dir(dir))
+    }
+
+    /// See `Pipe::env`.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(self, key: K, value: V) -> AsyncPipe {
+        self.map(|pipe| pipe.env(key, value))
+    }
+
+    /// See `Pipe::env_remove`.
+    pub fn env_remove<K: AsRef<OsStr>>(self, key: K) -> AsyncPipe {
+        self.map(|pipe| pipe.env_remove(key))
+    }
+
+    /// See `Pipe::env_clear`.
+    pub fn env_clear(self) -> AsyncPipe {
+        self.map(Pipe::env_clear)
+    }
+
+    /// See `Pipe::default_current_dir`.
+    pub fn default_current_dir<P: AsRef<Path>>(self, dir: P) -> AsyncPipe {
+        self.map(|pipe| pipe.default_current_dir(dir))
+    }
+
+    /// See `Pipe::default_env`.
+    pub fn default_env<K: AsRef<OsStr>, V: AsRef<OsStr>>(self, key: K, value: V) -> AsyncPipe {
+        self.map(|pipe| pipe.default_env(key, value))
+    }
+
+    /// See `Pipe::default_env_remove`.
+    pub fn default_env_remove<K: AsRef<OsStr>>(self, key: K) -> AsyncPipe {
+        self.map(|pipe| pipe.default_env_remove(key))
+    }
+
+    /// See `Pipe::default_env_clear`.
+    pub fn default_env_clear(self) -> AsyncPipe {
+        self.map(Pipe::default_env_clear)
+    }
+
+    /// See `Pipe::timeout`.
+    pub fn timeout(self, timeout: Duration) -> AsyncPipe {
+        self.map(|pipe| pipe.timeout(timeout))
+    }
+
+    /// See `Pipe::grace_period`.
+    pub fn grace_period(self, grace: Duration) -> AsyncPipe {
+        self.map(|pipe| pipe.grace_period(grace))
+    }
+
+    /// See `Pipe::own_process_group`.
+    pub fn own_process_group(self, own: bool) -> AsyncPipe {
+        self.map(|pipe| pipe.own_process_group(own))
+    }
+
+    /// Spawn every command and return a handle that owns all of them,
+    /// which the stdout of the final command can be read from.
+    ///
+    /// The commands are killed if the handle is dropped before they
+    /// exit, including when a future waiting on them is dropped.
+    pub fn spawn(self) -> Result<AsyncRunningPipeline> {
+        self.spawn_to(Target::Piped)
+    }
+
+    /// Run the pipe until every command has exited, collecting the
+    /// stdout of the final command along with the exit status of
+    /// every command in the pipe, just like `Pipe::output`.
+    ///
+    /// Dropping the returned future before it finishes kills
+    /// every command.
+    pub async fn output(self) -> Result<PipelineOutput> {
+        self.spawn()?.wait().await
+    }
+
+    /// Run the pipe until every command has exited, with the final
+    /// command writing straight to the stdout of this process, just
+    /// like `Pipe::wait_all`.
+    ///
+    /// Dropping the returned future before it finishes kills
+    /// every command.
+    pub async fn wait_all(self) -> Result<PipelineOutput> {
+        let mut running = self.spawn_to(Target::Inherit)?;
+        running.wait_into(io::sink()).await
+    }
+
+    /// Run the pipe with the stdout of the final command going straight
+    /// into the file at `path` until every command has exited, just like
+    /// `Pipe::finally_to_file`.
+    ///
+    /// Dropping the returned future before it finishes kills
+    /// every command.
+    pub async fn finally_to_file<P: AsRef<Path>>(
+        self,
+        path: P,
+        mode: FileMode,
+    ) -> Result<PipelineOutput> {
+        let file = mode.open(path.as_ref())?;
+        let mut running = self.spawn_to(Target::File(file))?;
+        running.wait_into(io::sink()).await
+    }
+
+    /// Spawn every command with tokio, piping each one into the next
+    /// and sending the stdout of the final one to `target`.
+    fn spawn_to(self, target: Target) -> Result<AsyncRunningPipeline> {
+        let mut pipe = self.pipe;
+        let stages = mem::replace(&mut pipe.stages, Ok(Vec::new()))?;
+        let stages = pipe.expand_stages(stages)?;
+        let timeout = pipe.pipeline.timeout;
+        let mut running = AsyncRunningPipeline {
+            running: Vec::new(),
+            stdout: None,
+            feeder: None,
+            policy: mem::take(&mut pipe.pipeline.policy),
+            timeout,
+            grace: pipe.pipeline.grace,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            group: None,
+        };
+
+        // If anything goes wrong, dropping `running` kills every
+        // command that was already spawned
+        let last = stages.len().saturating_sub(1);
+        let mut target = Some(target);
+        let mut previous = None;
+        for (index, stage) in stages.into_iter().enumerate() {
+            let stdout = match target.take() {
+                Some(target) if index == last => target,
+                other => {
+                    target = other;
+                    Target::Piped
+                }
+            };
+
+            let Prepared {
+                command,
+                source,
+                reader,
+            } = pipe.prepare_stage(stage, stdout, index, previous.take())?;
+            let line = command_line(&command);
+            let mut command = process::Command::from(command);
+            command.kill_on_drop(true);
+            let mut child = command
+                .spawn()
+                .map_err(|source| spawn_failed(index, command.as_std(), source))?;
+            drop(command);
+
+            if let Some(id) = child.id() {
+                pipe.spawned(id);
+                running.group = pipe.pipeline.group;
+            }
+            if let (Some(source), Some(stdin)) = (source, child.stdin.take()) {
+                let stdin = std_process::ChildStdin::from(stdin.into_owned_fd()?);
+                running.feeder = Some(input::feed(source, stdin));
+            }
+
+            // Stages in between hand their stdout to the next one the
+            // same way a `Pipe` does, and only the final one is async
+            let stdout = match (reader, child.stdout.take()) {
+                (Some(reader), _) => Some(reader),
+                (None, Some(stdout)) => Some(PipeReader::from(stdout.into_owned_fd()?)),
+                (None, None) => None,
+            };
+            if index == last {
+                running.stdout = match stdout {
+                    Some(stdout) => Some(Receiver::from_owned_fd(stdout.into())?),
+                    None => None,
+                };
+            } else {
+                previous = Some(Some(
+                    stdout.map_or(StageStdout::Redirected, StageStdout::Pipe),
+                ));
+            }
+
+            let limit = pipe.stderr_limit;
+            let stderr = child
+                .stderr
+                .take()
+                .map(|err| tokio::spawn(capture(err, limit)));
+            running.running.push(AsyncRunning {
+                command: line,
+                child,
+                stderr,
+            });
+        }
+
+        Ok(running)
+    }
+}
+
+/// A command that has been spawned as part of an `AsyncPipe`.
+struct AsyncRunning {
+    /// The command line the child was spawned from.
+    command: String,
+    child: Child,
+    /// The task collecting the child's stderr, if it's captured.
+    stderr: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+/// Every command of an `AsyncPipe` that has been spawned, returned
+/// by `AsyncPipe::spawn`.
+///
+/// Reading from it reads the stdout of the final command. If it's
+/// dropped before `wait` finishes, every command is killed.
+pub struct AsyncRunningPipeline {
+    /// Commands that were spawned, in pipeline order.
+    running: Vec<AsyncRunning>,
+    /// The stdout of the final command, if it's piped.
+    stdout: Option<Receiver>,
+    /// The thread writing the pipe's input into the first command.
+    feeder: Option<Feeder>,
+    /// Decides whether the pipe succeeded once every command exited.
+    policy: SuccessPolicy,
+    /// How long the pipe is allowed to run for, if there's a limit.
+    timeout: Option<Duration>,
+    /// How long commands get to exit after being sent `SIGTERM`
+    /// once the pipe has run out of time.
+    grace: Duration,
+    /// When the pipe runs out of time, from the moment it started.
+    deadline: Option<Instant>,
+    /// The process group every command runs in, if they were given
+    /// one of their own.
+    group: Option<Pid>,
+}
+
+impl AsyncRunningPipeline {
+    /// Return the process ID of every command that hasn't been
+    /// waited on yet, in pipeline order.
+    pub fn ids(&self) -> Vec<u32> {
+        self.running.iter().filter_map(|r| r.child.id()).collect()
+    }
+
+    /// See `RunningPipeline::group_id`.
+    pub fn group_id(&self) -> Option<u32> {
+        self.group.map(|group| group.as_raw() as u32)
+    }
+
+    /// Send `signal` to every command that's still running, the same
+    /// way `RunningPipeline::signal` does.
+    pub fn signal(&mut self, signal: Signal) -> std::io::Result<()> {
+        let unfinished = self.unfinished()?;
+        if unfinished.is_empty() {
+            return Ok(());
+        }
+        match self.group {
+            Some(group) => signal::killpg(group, signal)?,
+            None => {
+                for id in unfinished {
+                    signal::kill(Pid::from_raw(id as i32), signal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Kill every command that's still running. The commands still
+    /// have to be waited on with `wait` to find out how they exited.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        self.signal(Signal::SIGKILL)
+    }
+
+    /// Return the process IDs of the commands that haven't exited yet.
+    fn unfinished(&mut self) -> std::io::Result<Vec<u32>> {
+        let mut unfinished = Vec::new();
+        for running in &mut self.running {
+            if running.child.try_wait()?.is_none() {
+                unfinished.extend(running.child.id());
+            }
+        }
+        Ok(unfinished)
+    }
+
+    /// Wait until every command has exited, collecting whatever is
+    /// left of the stdout of the final command along with the exit
+    /// status of every command, just like `RunningPipeline::wait`.
+    pub async fn wait(mut self) -> Result<PipelineOutput> {
+        let mut stdout = Vec::new();
+        match self.wait_into(&mut stdout).await {
+            Ok(mut output) => {
+                output.stdout = stdout;
+                Ok(output)
+            }
+            Err(PipeError::TimedOut {
+                timeout,
+                mut output,
+            }) => {
+                output.stdout = stdout;
+                Err(PipeError::TimedOut { timeout, output })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait on every command and check how they finished, copying
+    /// the stdout of the final command into `out` in the meantime.
+    async fn wait_into<W: AsyncWrite + Unpin>(&mut self, mut out: W) -> Result<PipelineOutput> {
+        let stdout = self.stdout.take();
+        let copy = async move {
+            match stdout {
+                Some(mut stdout) => io::copy(&mut stdout, &mut out).await.map(|_| ()),
+                None => Ok(()),
+            }
+        };
+
+        let (copy, timed_out) = tokio::join!(copy, self.wait_children());
+        let stages = self.wait_running().await;
+        let fed = self.join_feeder().await;
+        copy?;
+        fed?;
+
+        let output = PipelineOutput {
+            stages: stages?,
+            stdout: Vec::new(),
+        };
+        match (timed_out?, self.timeout) {
+            (true, Some(timeout)) => Err(PipeError::TimedOut { timeout, output }),
+            _ => self.policy.check(output),
+        }
+    }
+
+    /// Wait for every command to exit before the deadline, if there is
+    /// one. Once it passes, the ones still running are sent `SIGTERM`,
+    /// and any left after the grace period are killed. Returns whether
+    /// the deadline passed.
+    async fn wait_children(&mut self) -> std::io::Result<bool> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return self.exited().await.map(|_| false),
+        };
+        if let Ok(exited) = time::timeout_at(deadline.into(), self.exited()).await {
+            return exited.map(|_| false);
+        }
+
+        self.signal(Signal::SIGTERM)?;
+        if time::timeout(self.grace, self.exited()).await.is_err() {
+            self.signal(Signal::SIGKILL)?;
+        }
+        self.exited().await?;
+        Ok(true)
+    }
+
+    /// Wait until every command has exited.
+    async fn exited(&mut self) -> std::io::Result<()> {
+        for running in &mut self.running {
+            running.child.wait().await?;
+        }
+        Ok(())
+    }
+
+    /// Wait on every command, returning how each of them finished
+    /// or the first error hit while waiting.
+    async fn wait_running(&mut self) -> Result<Vec<StageOutput>> {
+        let mut stages = Vec::new();
+        let mut error = None;
+        for mut running in mem::take(&mut self.running) {
+            let status = running.child.wait().await;
+            let stderr = match running.stderr {
+                Some(capture) => match capture.await {
+                    Ok(stderr) => stderr,
+                    Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+                    Err(e) => Err(e.into()),
+                },
+                None => Ok(Vec::new()),
+            };
+
+            match (status, stderr) {
+                (Ok(status), Ok(stderr)) => stages.push(StageOutput {
+                    command: running.command,
+                    status,
+                    stderr,
+                }),
+                (Err(e), _) | (_, Err(e)) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(PipeError::Io(e)),
+            None => Ok(stages),
+        }
+    }
+
+    /// Wait for the input of the pipe to be written, if it has any.
+    async fn join_feeder(&mut self) -> Result<()> {
+        let feeder = match self.feeder.take() {
+            Some(feeder) => feeder,
+            None => return Ok(()),
+        };
+        match task::spawn_blocking(move || feeder.join()).await {
+            Ok(Ok(fed)) => fed.map_err(PipeError::Io),
+            Ok(Err(panic)) => panic::resume_unwind(panic),
+            Err(e) => Err(PipeError::Io(e.into())),
+        }
+    }
+}
+
+impl AsyncRead for AsyncRunningPipeline {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut().stdout {
+            Some(ref mut stdout) => Pin::new(stdout).poll_read(cx, buf),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for AsyncRunningPipeline {
+    fn drop(&mut self) {
+        // Each child is also killed on its own when it's dropped, and
+        // reaped by tokio in the background afterwards
+        let _ = self.kill();
+    }
+}
+
+/// Read `stderr` until it's closed, keeping at most `limit` bytes
+/// of it just like a `Pipe` does.
+async fn capture(mut stderr: ChildStderr, limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut captured = Vec::new();
+    match limit {
+        Some(limit) => {
+            (&mut stderr)
+                .take(limit as u64)
+                .read_to_end(&mut captured)
+                .await?;
+            io::copy(&mut stderr, &mut io::sink()).await?;
+        }
+        None => {
+            stderr.read_to_end(&mut captured).await?;
+        }
+    }
+    Ok(captured)
+}
+
+#[tokio::test]
+async fn test_async_pipe() {
+    let out = AsyncPipe::parse("printf 'b\\na\\n' | sort | sh -c 'cat; echo err >&2' 2>&1")
+        .output()
+        .await
+        .unwrap();
+    assert_eq!("a\nb\nerr\n", String::from_utf8(out.stdout).unwrap());
+    assert_eq!(3, out.stages.len());
+
+    // Futures have to be `Send` to be spawned onto the runtime
+    let out = tokio::spawn(
+        AsyncPipe::new("cat")
+            .with_input("hello")
+            .then("sh -c 'cat; echo oops >&2; exit 3'")
+            .capture_stderr()
+            .output(),
+    )
+    .await
+    .unwrap();
+    match out {
+        Err(PipeError::StageFailed { stage, stderr, .. }) => {
+            assert_eq!(1, stage);
+            assert_eq!(b"oops\n", &stderr[..]);
+        }
+        other => panic!("expected a failed stage, got {:?}", other.map(|_| ())),
+    }
+
+    let out = AsyncPipe::new("true").wait_all().await.unwrap();
+    assert!(out.stages[0].status.success());
+
+    let path = std::env::temp_dir().join(format!("pipers-async-file-{}", std::process::id()));
+    for &mode in &[FileMode::Truncate, FileMode::Append] {
+        let out = AsyncPipe::new("echo hello")
+            .then("tr a-z A-Z")
+            .finally_to_file(&path, mode)
+            .await
+            .unwrap();
+        assert!(out.stdout.is_empty());
+    }
+    assert_eq!("HELLO\nHELLO\n", std::fs::read_to_string(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_async_pipe_read() {
+    let mut running = AsyncPipe::new("yes").then("head -n 3").spawn().unwrap();
+    let mut first = [0; 2];
+    running.read_exact(&mut first).await.unwrap();
+    assert_eq!(b"y\n", &first);
+
+    let out = running.wait().await.unwrap();
+    assert_eq!(b"y\ny\n", &out.stdout[..]);
+}
+
+#[tokio::test]
+async fn test_async_pipe_cancel() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let running = AsyncPipe::new("sleep 10").then("cat").spawn().unwrap();
+    let ids = running.ids();
+    assert!(time::timeout(Duration::from_millis(100), running.wait())
+        .await
+        .is_err());
+
+    // Dropping the future killed every command
+    let alive = |id: u32| {
+        let stat = std_process::Command::new("ps")
+            .args(["-o", "stat=", "-p", &id.to_string()])
+            .output()
+            .map(|out| out.stdout)
+            .unwrap_or_default();
+        !stat.is_empty() && !stat.starts_with(b"Z")
+    };
+    for _ in 0..100 {
+        if !ids.iter().any(|&id| alive(id)) {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!ids.into_iter().any(alive));
+
+    match AsyncPipe::new("sh -c 'echo started; exec sleep 10'")
+        .timeout(Duration::from_millis(100))
+        .output()
+        .await
+    {
+        Err(PipeError::TimedOut { output, .. }) => {
+            assert_eq!(b"started\n", &output.stdout[..]);
+            assert_eq!(Some(15), output.stages[0].status.signal());
+        }
+        other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+    }
+}
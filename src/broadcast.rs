@@ -0,0 +1,26 @@
+use std::io::{self, PipeWriter, Read, Write};
+
+/// Copy everything from `reader` into every one of `writers`, so that
+/// the slowest one sets the pace. A writer that can't be written to
+/// anymore, like one whose reader has exited, is dropped and the rest
+/// carry on. Once every writer is gone, `reader` is dropped as well.
+pub fn broadcast<R: Read>(mut reader: R, writers: Vec<PipeWriter>) -> io::Result<()> {
+    let mut writers: Vec<_> = writers.into_iter().map(Some).collect();
+    let mut buf = vec![0; 64 * 1024];
+    while writers.iter().any(Option::is_some) {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for writer in &mut writers {
+            if let Some(ref mut open) = *writer {
+                if open.write_all(&buf[..read]).is_err() {
+                    *writer = None;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::io::{self, Read};
+use std::thread::{self, JoinHandle};
+
+/// A thread collecting everything a stage writes to a pipe.
+pub type Capture = JoinHandle<io::Result<Vec<u8>>>;
+
+/// Read `reader` until it's closed on a separate thread, so that a
+/// stage can never block on a full pipe while others are waited on.
+///
+/// With a `limit` only that many bytes are kept. The rest is still
+/// read so that the stage can keep writing, but is thrown away.
+pub fn capture<R: Read + Send + 'static>(mut reader: R, limit: Option<usize>) -> Capture {
+    thread::spawn(move || {
+        let mut captured = Vec::new();
+        match limit {
+            Some(limit) => {
+                (&mut reader)
+                    .take(limit as u64)
+                    .read_to_end(&mut captured)?;
+                io::copy(&mut reader, &mut io::sink())?;
+            }
+            None => {
+                reader.read_to_end(&mut captured)?;
+            }
+        }
+        Ok(captured)
+    })
+}
@@ -0,0 +1,95 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The working directory and environment a command is spawned with,
+/// on top of whatever it would inherit from this process.
+#[derive(Debug, Default)]
+pub struct Environment {
+    dir: Option<PathBuf>,
+    /// Changes to the environment, applied in the order they were made.
+    changes: Vec<Change>,
+}
+
+/// A single change to the environment of a command.
+#[derive(Debug)]
+enum Change {
+    Set(OsString, OsString),
+    Remove(OsString),
+    Clear,
+}
+
+impl Environment {
+    pub fn current_dir(&mut self, dir: &Path) {
+        self.dir = Some(dir.to_path_buf());
+    }
+
+    pub fn env(&mut self, key: &OsStr, value: &OsStr) {
+        self.changes
+            .push(Change::Set(key.to_os_string(), value.to_os_string()));
+    }
+
+    pub fn env_remove(&mut self, key: &OsStr) {
+        self.changes.push(Change::Remove(key.to_os_string()));
+    }
+
+    pub fn env_clear(&mut self) {
+        self.changes.push(Change::Clear);
+    }
+
+    /// Return the directory a command runs in with `defaults` under
+    /// this environment. A relative directory is taken relative to
+    /// the one under it.
+    pub fn dir(&self, defaults: &Environment) -> Option<PathBuf> {
+        match (defaults.dir.as_ref(), self.dir.as_ref()) {
+            (Some(default), Some(dir)) => Some(default.join(dir)),
+            (default, dir) => dir.or(default).cloned(),
+        }
+    }
+
+    /// Set up `command` with `defaults` first, then anything that was
+    /// set on the command itself, and then this environment, so that
+    /// each one overrides the one before it.
+    pub fn apply(&self, defaults: &Environment, command: &mut Command) {
+        let own: Vec<_> = command
+            .get_envs()
+            .map(|(key, value)| (key.to_os_string(), value.map(OsStr::to_os_string)))
+            .collect();
+        let own_dir = command.get_current_dir().map(Path::to_path_buf);
+
+        for change in &defaults.changes {
+            change.apply(command);
+        }
+        for (key, value) in own {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        for change in &self.changes {
+            change.apply(command);
+        }
+
+        let dir = match (defaults.dir.as_ref(), own_dir) {
+            (Some(default), Some(own)) => Some(default.join(own)),
+            (default, own) => own.or_else(|| default.cloned()),
+        };
+        let dir = match (dir, self.dir.as_ref()) {
+            (Some(dir), Some(stage)) => Some(dir.join(stage)),
+            (dir, stage) => dir.or_else(|| stage.cloned()),
+        };
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+    }
+}
+
+impl Change {
+    fn apply(&self, command: &mut Command) {
+        match *self {
+            Change::Set(ref key, ref value) => command.env(key, value),
+            Change::Remove(ref key) => command.env_remove(key),
+            Change::Clear => command.env_clear(),
+        };
+    }
+}
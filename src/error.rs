@@ -0,0 +1,197 @@
+use crate::output::PipelineOutput;
+use crate::parse::ParseError;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::result;
+use std::time::Duration;
+
+/// Result type used throughout `pipers`.
+pub type Result<T> = result::Result<T, PipeError>;
+
+/// Everything that can go wrong while building or running a `Pipe`.
+#[derive(Debug)]
+pub enum PipeError {
+    /// A stage was given an empty command, so there
+    /// was no program to run.
+    EmptyCommand,
+    /// A command string couldn't be split into its arguments.
+    ParseError(ParseError),
+    /// The program of a stage couldn't be spawned.
+    SpawnFailed {
+        stage: usize,
+        program: String,
+        source: io::Error,
+    },
+    /// A file the pipe reads from or writes to couldn't be opened.
+    OpenFailed { path: PathBuf, source: io::Error },
+    /// A stage had no stdout to pipe into the next stage.
+    MissingStdout { stage: usize },
+    /// A stage runs a closure rather than a process, so there's
+    /// no `Child` to hand out for it.
+    NotAProcess { stage: usize },
+    /// A glob pattern in a stage didn't match any files while
+    /// `NoMatch::Error` was in use.
+    NoMatch { stage: usize, pattern: String },
+    /// A stage exited in a way the pipe's `SuccessPolicy`
+    /// doesn't accept.
+    StageFailed {
+        stage: usize,
+        command: String,
+        status: ExitStatus,
+        stderr: Vec<u8>,
+    },
+    /// The pipe was still running once its timeout passed, so its
+    /// stages were stopped. `output` holds everything collected
+    /// before they exited.
+    TimedOut {
+        timeout: Duration,
+        output: PipelineOutput,
+    },
+    /// A branch given to `Pipe::fan_out` failed, with `source`
+    /// saying how.
+    BranchFailed {
+        branch: usize,
+        source: Box<PipeError>,
+    },
+    /// Reading from or waiting on the running stages failed.
+    Io(io::Error),
+}
+
+impl PipeError {
+    /// Create a copy of this error for when the original has to
+    /// stay where it is. `io::Error`s can't be cloned, so they're
+    /// recreated from their kind and message.
+    pub(crate) fn duplicate(&self) -> PipeError {
+        let io = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match *self {
+            PipeError::EmptyCommand => PipeError::EmptyCommand,
+            PipeError::ParseError(ref e) => PipeError::ParseError(e.clone()),
+            PipeError::SpawnFailed {
+                stage,
+                ref program,
+                ref source,
+            } => PipeError::SpawnFailed {
+                stage,
+                program: program.clone(),
+                source: io(source),
+            },
+            PipeError::OpenFailed {
+                ref path,
+                ref source,
+            } => PipeError::OpenFailed {
+                path: path.clone(),
+                source: io(source),
+            },
+            PipeError::MissingStdout { stage } => PipeError::MissingStdout { stage },
+            PipeError::NotAProcess { stage } => PipeError::NotAProcess { stage },
+            PipeError::NoMatch { stage, ref pattern } => PipeError::NoMatch {
+                stage,
+                pattern: pattern.clone(),
+            },
+            PipeError::StageFailed {
+                stage,
+                ref command,
+                status,
+                ref stderr,
+            } => PipeError::StageFailed {
+                stage,
+                command: command.clone(),
+                status,
+                stderr: stderr.clone(),
+            },
+            PipeError::TimedOut {
+                timeout,
+                ref output,
+            } => PipeError::TimedOut {
+                timeout,
+                output: output.clone(),
+            },
+            PipeError::BranchFailed { branch, ref source } => PipeError::BranchFailed {
+                branch,
+                source: Box::new(source.duplicate()),
+            },
+            PipeError::Io(ref e) => PipeError::Io(io(e)),
+        }
+    }
+}
+
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PipeError::EmptyCommand => f.write_str("No command as input"),
+            PipeError::ParseError(ref e) => write!(f, "failed to parse command: {}", e),
+            PipeError::SpawnFailed {
+                stage,
+                ref program,
+                ref source,
+            } => write!(
+                f,
+                "failed to spawn stage {} (`{}`): {}",
+                stage, program, source
+            ),
+            PipeError::OpenFailed {
+                ref path,
+                ref source,
+            } => write!(f, "failed to open {}: {}", path.display(), source),
+            PipeError::MissingStdout { stage } => write!(f, "No stdout for stage {}", stage),
+            PipeError::NotAProcess { stage } => {
+                write!(f, "stage {} runs a closure, not a process", stage)
+            }
+            PipeError::NoMatch { stage, ref pattern } => {
+                write!(f, "no matches found for `{}` in stage {}", pattern, stage)
+            }
+            PipeError::StageFailed {
+                stage,
+                ref command,
+                status,
+                ..
+            } => write!(f, "stage {} (`{}`) failed with {}", stage, command, status),
+            PipeError::TimedOut { timeout, .. } => {
+                write!(f, "pipe timed out after {:?}", timeout)
+            }
+            PipeError::BranchFailed { branch, ref source } => {
+                write!(f, "branch {} failed: {}", branch, source)
+            }
+            PipeError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for PipeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PipeError::ParseError(ref e) => Some(e),
+            PipeError::SpawnFailed { ref source, .. } => Some(source),
+            PipeError::OpenFailed { ref source, .. } => Some(source),
+            PipeError::BranchFailed { ref source, .. } => Some(&**source),
+            PipeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for PipeError {
+    fn from(error: ParseError) -> PipeError {
+        PipeError::ParseError(error)
+    }
+}
+
+impl From<PipeError> for io::Error {
+    /// Turn a `PipeError` into an `io::Error`, which keeps the `PipeError`
+    /// around to be downcast to unless it was already an `io::Error`.
+    fn from(error: PipeError) -> io::Error {
+        match error {
+            PipeError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+impl From<io::Error> for PipeError {
+    fn from(error: io::Error) -> PipeError {
+        PipeError::Io(error)
+    }
+}
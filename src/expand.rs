@@ -0,0 +1,213 @@
+use crate::error::{PipeError, Result};
+use crate::parse::{ParseError, Part, Word};
+use glob::{self, MatchOptions, Pattern};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Where the values of variables come from when they're expanded.
+pub enum Variables {
+    /// The environment of this process.
+    Env,
+    /// The variables given to `Pipe::expand_with`.
+    Map(HashMap<String, OsString>),
+}
+
+impl Variables {
+    /// Look up the value of the variable called `name`.
+    fn get(&self, name: &str) -> Option<OsString> {
+        match *self {
+            Variables::Env => env::var_os(name),
+            Variables::Map(ref map) => map.get(name).cloned(),
+        }
+    }
+}
+
+/// What to do with a glob pattern that doesn't match any files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NoMatch {
+    /// Pass the pattern along as it was written, like a POSIX shell.
+    #[default]
+    Keep,
+    /// Leave the pattern out of the arguments, like `nullglob` in bash.
+    Drop,
+    /// Fail with `PipeError::NoMatch` before anything is spawned,
+    /// like `failglob` in bash.
+    Error,
+}
+
+/// Turn the words of the command in `stage` into its arguments,
+/// expanding variables if there are `variables` to use and
+/// matching glob patterns against files in `dir` if `globs` is set.
+pub fn args(
+    stage: usize,
+    words: &[Word],
+    variables: Option<&Variables>,
+    globs: Option<NoMatch>,
+    dir: Option<&Path>,
+) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for word in words {
+        check(word, variables)?;
+        let pattern = match globs {
+            Some(no_match) => pattern(word, variables).map(|p| (p, no_match)),
+            None => None,
+        };
+        let (pattern, no_match) = match pattern {
+            Some(pattern) => pattern,
+            None => {
+                args.extend(resolve(word, variables)?);
+                continue;
+            }
+        };
+
+        let before = args.len();
+        args.extend(matches(&pattern, dir));
+        if args.len() == before {
+            match no_match {
+                NoMatch::Keep => args.extend(resolve(word, variables)?),
+                NoMatch::Drop => {}
+                NoMatch::Error => {
+                    return Err(PipeError::NoMatch {
+                        stage,
+                        pattern: word.literal(),
+                    })
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Make sure every variable in `word` can be expanded if there are
+/// `variables` to expand it with.
+fn check(word: &Word, variables: Option<&Variables>) -> Result<()> {
+    for part in &word.0 {
+        if let (&Part::BadSubstitution { position }, Some(_)) = (part, variables) {
+            return Err(ParseError::BadSubstitution { position }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Turn a word into the argument it stands for, expanding its
+/// variables and `~` from `variables` if there are any to use.
+///
+/// `None` is returned for a word made up only of unquoted
+/// expansions that came out empty, since a shell drops those.
+/// A variable that isn't in a supported form is an error, unless
+/// nothing is expanded and it's left as it was written.
+pub fn resolve(word: &Word, variables: Option<&Variables>) -> Result<Option<OsString>> {
+    check(word, variables)?;
+    let mut arg = OsString::new();
+    let mut quoted = false;
+    let mut expanded = false;
+    for part in &word.0 {
+        match *part {
+            Part::Unquoted(ref text) => arg.push(text),
+            Part::BadSubstitution { .. } => arg.push("$"),
+            Part::Quoted(ref text) => {
+                quoted = true;
+                arg.push(text);
+            }
+            Part::Var { .. } | Part::Tilde => {
+                expanded |= variables.is_some();
+                arg.push(value(part, variables));
+            }
+        }
+    }
+
+    if expanded && !quoted && arg.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(arg))
+    }
+}
+
+/// Return the value a variable or `~` expands to, or the way it
+/// was written if nothing is expanded.
+fn value(part: &Part, variables: Option<&Variables>) -> OsString {
+    match (part, variables) {
+        (Part::Var { name, default, .. }, Some(variables)) => {
+            match (variables.get(name), default.as_ref()) {
+                (Some(value), _) if !value.is_empty() => value,
+                (_, Some(default)) => default.into(),
+                _ => OsString::new(),
+            }
+        }
+        (Part::Tilde, Some(variables)) => variables.get("HOME").unwrap_or_else(|| "~".into()),
+        (Part::Var { raw, .. }, None) => raw.into(),
+        (Part::Tilde, None) => "~".into(),
+        (Part::Unquoted(text), _) | (Part::Quoted(text), _) => text.into(),
+        (Part::BadSubstitution { .. }, _) => "$".into(),
+    }
+}
+
+/// Build the glob pattern a word stands for, or return `None` if
+/// it has no unquoted `*`, `?` or `[` in it and isn't a pattern.
+///
+/// Everything besides the unquoted text is escaped so that it only
+/// ever matches itself, including the values of variables.
+fn pattern(word: &Word, variables: Option<&Variables>) -> Option<String> {
+    let mut pattern = String::new();
+    let mut special = false;
+    for part in &word.0 {
+        match *part {
+            Part::Unquoted(ref text) => {
+                special |= text.contains(['*', '?', '[']);
+                pattern.push_str(text);
+            }
+            Part::Quoted(ref text) => pattern.push_str(&Pattern::escape(text)),
+            Part::BadSubstitution { .. } => pattern.push('$'),
+            // A value that isn't valid UTF-8 can't be put in a pattern
+            Part::Var { .. } | Part::Tilde => {
+                pattern.push_str(&Pattern::escape(value(part, variables).to_str()?))
+            }
+        }
+    }
+
+    if special {
+        Some(pattern)
+    } else {
+        None
+    }
+}
+
+/// Return the paths matching `pattern` in sorted order, taking a
+/// relative pattern relative to `dir` if there is one. A pattern that
+/// isn't valid, like `[a`, doesn't match anything, and neither do
+/// files starting with `.` unless the pattern spells out the `.`.
+fn matches(pattern: &str, dir: Option<&Path>) -> Vec<OsString> {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+
+    // The matches are returned relative to `dir` again, just like
+    // they would be for a command run from inside of it
+    let dir = match dir.and_then(Path::to_str) {
+        Some(dir) if Path::new(pattern).is_relative() => Some(dir),
+        _ => None,
+    };
+    let pattern = match dir {
+        Some(dir) => format!("{}/{}", Pattern::escape(dir), pattern),
+        None => pattern.to_string(),
+    };
+
+    match glob::glob_with(&pattern, options) {
+        Ok(paths) => paths
+            .filter_map(|path| path.ok())
+            .map(|path| match dir {
+                Some(dir) => path
+                    .strip_prefix(dir)
+                    .map(Path::to_path_buf)
+                    .unwrap_or(path),
+                None => path,
+            })
+            .map(PathBuf::into_os_string)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
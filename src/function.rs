@@ -0,0 +1,171 @@
+use nix::sys::signal::Signal;
+use std::io::{self, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::panic;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A closure run as a stage of a `Pipe`, reading what the stage
+/// before it writes and writing what the stage after it reads.
+pub type StageFn = dyn FnOnce(&mut dyn Read, &mut dyn Write) -> io::Result<()> + Send;
+
+/// Copy everything from `reader` into both `writer` and `side`, like
+/// the `tee` command. Once writing into `side` fails, the rest is only
+/// copied into `writer`, and the error is returned at the end.
+pub fn tee(reader: &mut dyn Read, writer: &mut dyn Write, side: &mut dyn Write) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut failed = None;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..read])?;
+        if failed.is_none() {
+            failed = side.write_all(&buf[..read]).err();
+        }
+    }
+
+    match failed {
+        Some(e) => Err(e),
+        None => side.flush(),
+    }
+}
+
+/// A closure running as a stage on a thread of its own.
+pub struct Worker {
+    thread: JoinHandle<io::Result<()>>,
+    stopped: Stop,
+}
+
+/// A handle for stopping a closure stage from anywhere.
+#[derive(Clone)]
+pub struct Stop(Arc<AtomicI32>);
+
+impl Stop {
+    /// Make every read and write of the closure fail from now on, the
+    /// closest a thread can get to being sent `signal`. A closure that
+    /// stops reading and writing altogether can't be stopped.
+    pub fn stop(&self, signal: Signal) {
+        let _ = self
+            .0
+            .compare_exchange(0, signal as i32, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Return the signal the stage was stopped with, or 0 if it wasn't.
+    fn signal(&self) -> i32 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Run `f` on a separate thread, reading from `reader` and writing
+/// into `writer`. Both are closed once it returns, just like the
+/// stdin and stdout of a command that exits.
+pub fn spawn(
+    f: Box<StageFn>,
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+) -> Worker {
+    let stopped = Stop(Arc::new(AtomicI32::new(0)));
+    let mut reader = Stoppable {
+        inner: reader,
+        stopped: stopped.clone(),
+    };
+    let mut writer = Stoppable {
+        inner: writer,
+        stopped: stopped.clone(),
+    };
+    let thread = thread::spawn(move || {
+        f(&mut reader, &mut writer)?;
+        writer.flush()
+    });
+    Worker { thread, stopped }
+}
+
+impl Worker {
+    /// Return whether the closure has returned.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// See `Stop::stop`.
+    pub fn stop(&self, signal: Signal) {
+        self.stopped.stop(signal);
+    }
+
+    /// Return a handle for stopping the closure from elsewhere.
+    pub fn stopper(&self) -> Stop {
+        self.stopped.clone()
+    }
+
+    /// Wait for the closure to return, turning how it did into the
+    /// exit status a command would have had, along with the message
+    /// of the error it returned in place of a stderr.
+    ///
+    /// A closure that was stopped looks killed by the signal it was
+    /// stopped with, and one that failed to write because the next
+    /// stage exited looks killed by `SIGPIPE`. Any other error looks
+    /// like exiting with 1.
+    pub fn wait(self) -> (ExitStatus, Vec<u8>) {
+        let returned = match self.thread.join() {
+            Ok(returned) => returned,
+            Err(panic) => panic::resume_unwind(panic),
+        };
+        match (returned, self.stopped.signal()) {
+            (Ok(()), _) => (ExitStatus::from_raw(0), Vec::new()),
+            (Err(_), stopped) if stopped != 0 => (ExitStatus::from_raw(stopped), Vec::new()),
+            (Err(ref e), _) if e.kind() == io::ErrorKind::BrokenPipe => {
+                (ExitStatus::from_raw(Signal::SIGPIPE as i32), Vec::new())
+            }
+            (Err(e), _) => (
+                ExitStatus::from_raw(1 << 8),
+                format!("{}\n", e).into_bytes(),
+            ),
+        }
+    }
+
+    /// Wait for the closure to return without caring how it did.
+    pub fn join(self) {
+        let _ = self.thread.join();
+    }
+}
+
+/// The reader or writer handed to a closure, which fails once
+/// the stage has been stopped.
+struct Stoppable<T> {
+    inner: T,
+    stopped: Stop,
+}
+
+impl<T> Stoppable<T> {
+    fn check(&self) -> io::Result<()> {
+        match self.stopped.signal() {
+            0 => Ok(()),
+            // Not `Interrupted`, which `io::copy` and friends retry on
+            _ => Err(io::Error::other("stage was stopped")),
+        }
+    }
+}
+
+impl<R: Read> Read for Stoppable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Write> Write for Stoppable<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check()?;
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,60 @@
+use crate::error::{PipeError, Result};
+use std::fs::File;
+use std::io::{self, Cursor, PipeReader, Read};
+use std::path::PathBuf;
+use std::process::{ChildStdin, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Where the first stage of a pipe reads its stdin from.
+pub enum Input {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+    Reader(Box<dyn Read + Send>),
+    /// The read end of a pipe that's given to the stage as it is.
+    Pipe(PipeReader),
+}
+
+/// A thread writing the input of a pipe into its first stage.
+pub type Feeder = JoinHandle<io::Result<()>>;
+
+impl Input {
+    /// Return the stdin to give the first stage, along with the
+    /// data that has to be fed into it once it's spawned, if any.
+    pub fn into_stdio(self) -> Result<(Stdio, Option<Box<dyn Read + Send>>)> {
+        match self {
+            Input::Bytes(bytes) => Ok((Stdio::piped(), Some(Box::new(Cursor::new(bytes))))),
+            Input::File(path) => match File::open(&path) {
+                Ok(file) => Ok((Stdio::from(file), None)),
+                Err(source) => Err(PipeError::OpenFailed { path, source }),
+            },
+            Input::Reader(reader) => Ok((Stdio::piped(), Some(reader))),
+            Input::Pipe(reader) => Ok((Stdio::from(reader), None)),
+        }
+    }
+
+    /// Return a reader for a first stage that runs in this process
+    /// instead of being spawned, which reads the input directly.
+    pub fn into_reader(self) -> Result<Box<dyn Read + Send>> {
+        match self {
+            Input::Bytes(bytes) => Ok(Box::new(Cursor::new(bytes))),
+            Input::File(path) => match File::open(&path) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(source) => Err(PipeError::OpenFailed { path, source }),
+            },
+            Input::Reader(reader) => Ok(reader),
+            Input::Pipe(reader) => Ok(Box::new(reader)),
+        }
+    }
+}
+
+/// Copy everything from `source` into `stdin` on a separate thread, so
+/// that a large input can't block while the pipe's output fills up.
+///
+/// A stage that exits without reading all of its input is not an
+/// error, just like in a shell.
+pub fn feed(mut source: Box<dyn Read + Send>, mut stdin: ChildStdin) -> Feeder {
+    thread::spawn(move || match io::copy(&mut source, &mut stdin) {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map(|_| ()),
+    })
+}
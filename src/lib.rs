@@ -1,12 +1,123 @@
 #![allow(dead_code)]
-use std::io::{Error, ErrorKind, Result};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+#![forbid(unsafe_code)]
+
+use std::any;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, IsTerminal, PipeReader, Read, Write};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::panic;
+use std::path::{Path, PathBuf};
 use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tokio")]
+mod async_pipe;
+mod broadcast;
+mod capture;
+mod environment;
+mod error;
+mod expand;
+mod function;
+mod input;
+mod lines;
+mod output;
+mod parse;
+mod policy;
+mod redirect;
+mod running;
+mod sink;
+mod timeout;
+
+use crate::environment::Environment;
+use crate::expand::Variables;
+use crate::function::StageFn;
+use crate::input::Input;
+use crate::parse::{Parsed, Redirect, Word};
+use crate::redirect::{StageStdout, Target};
+use crate::running::{Process, Running};
+use nix::unistd::Pid;
+
+#[cfg(feature = "tokio")]
+pub use crate::async_pipe::{AsyncPipe, AsyncRunningPipeline};
+pub use crate::error::{PipeError, Result};
+pub use crate::expand::NoMatch;
+pub use crate::lines::{Lines, Split};
+pub use crate::output::{FanOutOutput, PipelineOutput, SinkOutput, StageOutput};
+pub use crate::parse::ParseError;
+pub use crate::policy::{StagePredicate, SuccessPolicy};
+pub use crate::redirect::{FileMode, Stderr};
+pub use crate::running::{OnDrop, RunningPipeline};
+pub use nix::sys::signal::Signal;
 
 /// Data structure used to hold processes
 /// and allows for the chaining of commands
+///
+/// Commands are only spawned once the pipe is run with
+/// `output`, `wait_all`, `spawn` or `finally`, or when `peek` is used.
 pub struct Pipe {
-    child: Result<Child>,
+    /// Commands that haven't been spawned yet, or the first
+    /// error that happened while chaining them.
+    stages: Result<Vec<Stage>>,
+    /// Commands that were already spawned by a `peek`, along with
+    /// everything needed to wait on them.
+    pipeline: RunningPipeline,
+    /// What to give the first command as its stdin, if anything.
+    input: Option<Input>,
+    /// Whether the stderr of every command is collected.
+    capture_stderr: bool,
+    /// How much of each command's stderr to keep when it's collected.
+    stderr_limit: Option<usize>,
+    /// Where variables in command strings are looked up, if they're
+    /// expanded at all.
+    variables: Option<Variables>,
+    /// What to do with glob patterns that don't match anything, if
+    /// glob patterns in command strings are expanded at all.
+    globs: Option<NoMatch>,
+    /// The working directory and environment every command starts
+    /// from, which each command can override.
+    defaults: Environment,
+    /// Whether the commands are put into a process group of their own,
+    /// or `None` until the first command decides it.
+    own_group: Option<bool>,
+}
+
+/// A command waiting to be spawned as part of a `Pipe`.
+struct Stage {
+    run: Run,
+    /// The redirections written in the command string, which are
+    /// applied in order once it's spawned.
+    redirects: Vec<Redirect>,
+    /// Where the stderr of the command goes, if it was set for this
+    /// command in particular with `Pipe::stderr`.
+    stderr: Option<Stderr>,
+    /// The working directory and environment set for this command
+    /// in particular.
+    environment: Environment,
+}
+
+/// What a `Stage` runs.
+enum Run {
+    /// A `Command` that was set up by the caller.
+    Command(Command),
+    /// The words of a command string, which are only turned into
+    /// arguments once it's spawned so that they can be expanded.
+    Words(Vec<Word>),
+    /// A closure run on a thread of this process, along with its name.
+    Fn(String, Box<StageFn>),
+}
+
+impl From<Command> for Stage {
+    fn from(command: Command) -> Stage {
+        Stage {
+            run: Run::Command(command),
+            redirects: Vec::new(),
+            stderr: None,
+            environment: Environment::default(),
+        }
+    }
 }
 
 impl Pipe {
@@ -15,80 +126,1123 @@ impl Pipe {
     /// cause the eventual end of the piping to have
     /// an error returned. Make sure you place in an
     /// actual command.
+    ///
+    /// The command is split into arguments using the same
+    /// quoting rules as a POSIX shell, so `grep "hello world"`
+    /// passes `hello world` as a single argument. A command
+    /// that can't be split, like one with an unterminated
+    /// quote, also causes an error to be returned.
+    ///
+    /// Unquoted redirections are applied to the command instead
+    /// of being passed to it as arguments. `< file`, `> file`,
+    /// `>> file`, `2> file`, `2>> file`, `2>&1`, `&> file` and
+    /// `&>> file` are supported. Like in a shell they're applied in
+    /// order, so `2>&1` sends the stderr wherever the stdout goes at
+    /// that point: `2>&1 > file` sends the stderr down the pipe and
+    /// the stdout into `file`.
     pub fn new(command: &str) -> Pipe {
-        let mut split = command.split_whitespace();
-        let command = match split.next() {
-            Some(x) => x,
-            None => return pipe_new_error("No command as input"),
-        };
-        let args = split.collect::<Vec<&str>>();
+        Pipe::start(stage_from_str(command).map(|stage| vec![stage]))
+    }
+
+    /// Creates a new `Pipe` from a program and its arguments. The
+    /// arguments are passed along exactly as they are without being
+    /// split or unquoted, so they can contain whitespace, quotes or
+    /// bytes that aren't valid UTF-8.
+    pub fn new_args<P, I, S>(program: P, args: I) -> Pipe
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new(program);
+        command.args(args);
+        Pipe::from_command(command)
+    }
+
+    /// Creates a new `Pipe` from a `Command` that has already been
+    /// set up, for when you need to set things like environment
+    /// variables, the working directory or arguments that contain
+    /// whitespace.
+    ///
+    /// The stdout of the command is always replaced so that it can
+    /// be piped into the next command. Its stderr is left as it is,
+    /// unless `stderr`, `capture_stderr` or a redirect says otherwise.
+    pub fn from_command(command: Command) -> Pipe {
+        Pipe::start(Ok(vec![command.into()]))
+    }
+
+    /// Creates a new `Pipe` from a whole pipeline written the
+    /// way a shell would take it, like `ls / | grep usr | head -c 1`.
+    ///
+    /// The pipeline is split into stages on every `|` that isn't
+    /// quoted and each stage is split into arguments the same way
+    /// as `Pipe::new`. A stage without a command, like in `a || b`
+    /// or with a trailing `|`, causes an error to be returned that
+    /// says where the empty stage is.
+    pub fn parse(pipeline: &str) -> Pipe {
+        Pipe::start(
+            parse::split_pipeline(pipeline)
+                .map_err(PipeError::from)
+                .and_then(|stages| stages.into_iter().map(stage_from_parsed).collect()),
+        )
+    }
 
+    /// Creates a `Pipe` out of the stages it starts with.
+    fn start(stages: Result<Vec<Stage>>) -> Pipe {
         Pipe {
-            child: Command::new(command)
-                .args(args.as_slice())
-                .stdout(Stdio::piped())
-                .spawn(),
+            stages,
+            pipeline: RunningPipeline::new(),
+            input: None,
+            capture_stderr: false,
+            stderr_limit: None,
+            variables: None,
+            globs: None,
+            defaults: Environment::default(),
+            own_group: None,
         }
     }
 
     /// This is used to chain commands together. Use this for each
     /// command that you want to pipe.
     pub fn then(self, command: &str) -> Pipe {
-        let stdout = match self.child {
-            Ok(child) => match child.stdout {
-                Some(stdout) => stdout,
-                None => return pipe_new_error("No stdout for a command"),
-            },
-            Err(e) => return pipe_error(Err(e)),
+        self.push(stage_from_str(command))
+    }
+
+    /// This is used to chain a program and its arguments onto the pipe.
+    /// Like `Pipe::new_args` the arguments are passed along exactly as
+    /// they are.
+    pub fn then_args<P, I, S>(self, program: P, args: I) -> Pipe
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new(program);
+        command.args(args);
+        self.then_command(command)
+    }
+
+    /// This is used to chain a `Command` that has already been set up
+    /// onto the pipe, just like `then` does for a command string.
+    ///
+    /// The stdin and stdout of the command are always replaced so
+    /// that it reads from the previous command and can be piped
+    /// into the next one.
+    pub fn then_command(self, command: Command) -> Pipe {
+        self.push(Ok(command.into()))
+    }
+
+    /// This is used to chain a closure onto the pipe, which runs on a
+    /// thread of this process instead of being spawned. It's given the
+    /// stdout of the previous command to read from and a writer that
+    /// the next command reads from, both of which are closed once it
+    /// returns.
+    ///
+    /// The closure counts as a stage like any other. It's named after
+    /// its type in `StageOutput::command`, and returning an error makes
+    /// it look like a command that exited with 1, with the message of
+    /// the error in place of its stderr. When the next command exits
+    /// before reading everything, writing fails with `BrokenPipe`,
+    /// which looks like being killed by `SIGPIPE` if it's returned.
+    ///
+    /// Closures can't be killed, so when the pipe times out or is
+    /// killed, every read and write they do from then on fails
+    /// instead. A closure that stops reading and writing altogether
+    /// has to return on its own before the pipe can finish.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    /// use std::io::{BufRead, BufReader, Write};
+    ///
+    /// let out = Pipe::new("printf 'a\nb\n'")
+    ///     .then_fn(|reader, writer| {
+    ///         for line in BufReader::new(reader).lines() {
+    ///             writeln!(writer, "<{}>", line?)?;
+    ///         }
+    ///         Ok(())
+    ///     })
+    ///     .then("tac")
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"<b>\n<a>\n", &out.stdout[..]);
+    /// ```
+    pub fn then_fn<F>(self, f: F) -> Pipe
+    where
+        F: FnOnce(&mut dyn Read, &mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.push(Ok(stage_from_fn(any::type_name::<F>(), Box::new(f))))
+    }
+
+    /// Copy the stdout of the last command chained so far into `sink`
+    /// as well as into the next command, like `| tee` in a shell but
+    /// without spawning `tee`. This is done by a closure stage named
+    /// `tee`, which works just like one from `then_fn`.
+    ///
+    /// Every chunk is written into the next command first and then
+    /// into `sink`, so a slow sink slows the pipe down rather than
+    /// piling up data in memory. If writing into `sink` fails, the
+    /// rest is still passed on to the next command, and the stage
+    /// then fails with the error once it's done, just like `tee` does.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    /// use std::io;
+    ///
+    /// let out = Pipe::new("echo hello")
+    ///     .tee(io::stderr())
+    ///     .then("tr a-z A-Z")
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"HELLO\n", &out.stdout[..]);
+    /// ```
+    pub fn tee<W: Write + Send + 'static>(self, mut sink: W) -> Pipe {
+        let tee = move |reader: &mut dyn Read, writer: &mut dyn Write| {
+            function::tee(reader, writer, &mut sink)
         };
+        self.push(Ok(stage_from_fn("tee", Box::new(tee))))
+    }
 
-        let mut split = command.split_whitespace();
-        let command = match split.next() {
-            Some(x) => x,
-            None => return pipe_new_error("No command as input"),
+    /// Copy the stdout of the last command chained so far into the
+    /// file at `path` as well as into the next command, like `| tee`
+    /// or `| tee -a` in a shell. The file is opened once the pipe runs,
+    /// and failing to do so is handled like failing to write into
+    /// the sink given to `tee`.
+    pub fn tee_file<P: AsRef<Path>>(self, path: P, mode: FileMode) -> Pipe {
+        let path = path.as_ref().to_path_buf();
+        let name = format!("tee {}", path.display());
+        let tee = move |reader: &mut dyn Read, writer: &mut dyn Write| match mode.open(&path) {
+            Ok(mut file) => function::tee(reader, writer, &mut file),
+            Err(e) => {
+                io::copy(reader, writer)?;
+                Err(e.into())
+            }
         };
-        let args = split.collect::<Vec<&str>>();
-        let stdio = unsafe { Stdio::from_raw_fd(stdout.as_raw_fd()) };
+        self.push(Ok(stage_from_fn(&name, Box::new(tee))))
+    }
 
-        Pipe {
-            child: Command::new(command)
-                .args(args.as_slice())
-                .stdout(Stdio::piped())
-                .stdin(stdio)
-                .spawn(),
+    /// Add `stage` as the last stage of the pipe, or keep the
+    /// first error passing down the chain.
+    fn push(mut self, stage: Result<Stage>) -> Pipe {
+        self.stages = match (self.stages, stage) {
+            (Ok(mut stages), Ok(stage)) => {
+                stages.push(stage);
+                Ok(stages)
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+        self
+    }
+
+    /// Set the policy that decides whether the pipe succeeded once
+    /// every command has exited. By default only the final command
+    /// has to succeed, just like in a shell.
+    pub fn policy(mut self, policy: SuccessPolicy) -> Pipe {
+        self.pipeline.policy = policy;
+        self
+    }
+
+    /// Feed `bytes` to the stdin of the first command.
+    ///
+    /// The bytes are written from a separate thread while the pipe
+    /// runs, so a large input can't block against a full output.
+    pub fn with_input<B: Into<Vec<u8>>>(mut self, bytes: B) -> Pipe {
+        self.input = Some(Input::Bytes(bytes.into()));
+        self
+    }
+
+    /// Give the first command the file at `path` as its stdin, just
+    /// like `< path` in a shell. The file is opened once the pipe runs.
+    pub fn stdin_file<P: AsRef<Path>>(mut self, path: P) -> Pipe {
+        self.input = Some(Input::File(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Feed everything read from `reader` to the stdin of the first
+    /// command. Like `with_input`, it's copied from a separate thread.
+    pub fn stdin_reader<R: Read + Send + 'static>(mut self, reader: R) -> Pipe {
+        self.input = Some(Input::Reader(Box::new(reader)));
+        self
+    }
+
+    /// Collect the stderr of every command instead of letting it go
+    /// to the stderr of this process. Each command's stderr is kept
+    /// separately and returned along with its exit status.
+    pub fn capture_stderr(mut self) -> Pipe {
+        self.capture_stderr = true;
+        self
+    }
+
+    /// Choose where the stderr of the last command chained so far
+    /// goes, like `2>/dev/null` or `2>&1` placed after a command in
+    /// a shell. This takes priority over `capture_stderr`. A relative
+    /// `Stderr::File` is taken relative to the directory the command
+    /// runs in.
+    ///
+    /// ```rust
+    /// use pipers::{Pipe, Stderr};
+    ///
+    /// let out = Pipe::new("ls /pipers-does-not-exist")
+    ///     .stderr(Stderr::Merge)
+    ///     .then("wc -l")
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"1\n", &out.stdout[..]);
+    /// ```
+    pub fn stderr(mut self, stderr: Stderr) -> Pipe {
+        if let Ok(Some(stage)) = self.stages.as_mut().map(|stages| stages.last_mut()) {
+            stage.stderr = Some(stderr);
         }
+        self
+    }
+
+    /// Run the last command chained so far in `dir`. A relative `dir`
+    /// is taken relative to the directory set with `default_current_dir`,
+    /// if there is one.
+    ///
+    /// Relative paths in the redirections and glob patterns written in
+    /// the command string are taken relative to `dir` as well.
+    pub fn current_dir<P: AsRef<Path>>(self, dir: P) -> Pipe {
+        self.last_environment(|env| env.current_dir(dir.as_ref()))
+    }
+
+    /// Set the environment variable `key` to `value` for the last
+    /// command chained so far, overriding `default_env`.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    ///
+    /// let out = Pipe::new("sh -c 'echo $GREETING'")
+    ///     .env("GREETING", "hello")
+    ///     .then("sh -c 'cat; echo $GREETING'")
+    ///     .default_env("GREETING", "bye")
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"hello\nbye\n", &out.stdout[..]);
+    /// ```
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(self, key: K, value: V) -> Pipe {
+        self.last_environment(|env| env.env(key.as_ref(), value.as_ref()))
+    }
+
+    /// Remove the environment variable `key` for the last command
+    /// chained so far, whether it was inherited or set as a default.
+    pub fn env_remove<K: AsRef<OsStr>>(self, key: K) -> Pipe {
+        self.last_environment(|env| env.env_remove(key.as_ref()))
+    }
+
+    /// Start the last command chained so far with an empty environment,
+    /// apart from variables set for it with `env` afterwards.
+    pub fn env_clear(self) -> Pipe {
+        self.last_environment(Environment::env_clear)
+    }
+
+    /// Run every command in `dir`, unless it's given a different
+    /// one with `current_dir`.
+    pub fn default_current_dir<P: AsRef<Path>>(mut self, dir: P) -> Pipe {
+        self.defaults.current_dir(dir.as_ref());
+        self
+    }
+
+    /// Set the environment variable `key` to `value` for every command.
+    /// Commands can still override it with `env` or `env_remove`, as
+    /// can a `Command` that sets it itself.
+    pub fn default_env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Pipe {
+        self.defaults.env(key.as_ref(), value.as_ref());
+        self
+    }
+
+    /// Remove the environment variable `key` for every command.
+    pub fn default_env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Pipe {
+        self.defaults.env_remove(key.as_ref());
+        self
+    }
+
+    /// Start every command with an empty environment, apart from
+    /// variables set afterwards with `default_env` or `env`.
+    pub fn default_env_clear(mut self) -> Pipe {
+        self.defaults.env_clear();
+        self
+    }
+
+    /// Change the environment of the last stage chained so far.
+    fn last_environment<F: FnOnce(&mut Environment)>(mut self, change: F) -> Pipe {
+        if let Ok(Some(stage)) = self.stages.as_mut().map(|stages| stages.last_mut()) {
+            change(&mut stage.environment);
+        }
+        self
+    }
+
+    /// Only keep the first `limit` bytes of each command's captured
+    /// stderr. Anything after that is still read so that the command
+    /// doesn't block, but is thrown away.
+    pub fn stderr_limit(mut self, limit: usize) -> Pipe {
+        self.stderr_limit = Some(limit);
+        self
+    }
+
+    /// Expand variables like `$HOME`, `${USER}` and `${EDITOR:-vi}`
+    /// along with a leading `~` in every command string, using the
+    /// environment of this process.
+    ///
+    /// Nothing is expanded inside single quotes. A variable that isn't
+    /// set expands to nothing, and a word made up of nothing else is
+    /// dropped. Unlike a shell, an expanded value is never split on
+    /// whitespace or matched against files, so it always stays a
+    /// single argument. Any other `${...}` form, like `${A%b}`, fails
+    /// with `ParseError::BadSubstitution`, while it's passed along as
+    /// it was written when nothing is expanded.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    ///
+    /// let out = Pipe::new("echo ${PIPERS_UNSET:-fallback} '$HOME'")
+    ///     .expand_env()
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"fallback $HOME\n", &out.stdout[..]);
+    /// ```
+    pub fn expand_env(mut self) -> Pipe {
+        self.variables = Some(Variables::Env);
+        self
+    }
+
+    /// Expand variables and a leading `~` in every command string just
+    /// like `expand_env`, but look them up in `variables` instead of
+    /// the environment. A `~` expands to the `HOME` given here.
+    pub fn expand_with<I, K, V>(mut self, variables: I) -> Pipe
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<OsString>,
+    {
+        let map = variables
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect();
+        self.variables = Some(Variables::Map(map));
+        self
+    }
+
+    /// Expand unquoted glob patterns like `*.rs`, `file?.txt`, `[ab]*`
+    /// and `src/**/*.rs` in every command string into the paths that
+    /// match them, in sorted order. This is done by the pipe itself
+    /// without running a shell.
+    ///
+    /// Like in a shell, `*` and `?` don't match a leading `.` or a `/`,
+    /// quoted or escaped characters only ever match themselves, and
+    /// patterns are matched relative to the directory the command runs
+    /// in. `no_match` decides what happens to a pattern that
+    /// doesn't match any files.
+    ///
+    /// ```rust
+    /// use pipers::{NoMatch, Pipe};
+    ///
+    /// let out = Pipe::new("ls src/*.rs '*.rs'")
+    ///     .glob(NoMatch::Drop)
+    ///     .then("grep -c lib.rs")
+    ///     .output()
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"1\n", &out.stdout[..]);
+    /// ```
+    pub fn glob(mut self, no_match: NoMatch) -> Pipe {
+        self.globs = Some(no_match);
+        self
+    }
+
+    /// Limit how long the pipe can run for, counting from when its
+    /// commands are spawned. This applies while they're waited on, as
+    /// well as while the stdout of a pipe from `spawn`, `lines` or
+    /// `split` is read, but not to the `Child` from `finally`.
+    ///
+    /// Once `timeout` passes, every command still running is sent
+    /// `SIGTERM`, and any still running after the grace period set
+    /// with `grace_period` are killed. `PipeError::TimedOut` is then
+    /// returned with everything collected up to that point.
+    ///
+    /// ```rust
+    /// use pipers::{Pipe, PipeError};
+    /// use std::time::Duration;
+    ///
+    /// match Pipe::new("sh -c 'echo started; exec sleep 10'")
+    ///     .timeout(Duration::from_millis(100))
+    ///     .output()
+    /// {
+    ///     Err(PipeError::TimedOut { output, .. }) => assert_eq!(b"started\n", &output.stdout[..]),
+    ///     _ => panic!("the pipe should have timed out"),
+    /// }
+    /// ```
+    pub fn timeout(mut self, timeout: Duration) -> Pipe {
+        self.pipeline.timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long commands are given to exit after being sent
+    /// `SIGTERM` when the pipe times out, before they're killed.
+    /// This is 2 seconds by default.
+    pub fn grace_period(mut self, grace: Duration) -> Pipe {
+        self.pipeline.grace = grace;
+        self
+    }
+
+    /// Choose whether the commands are put into a process group of
+    /// their own.
+    ///
+    /// Just like a job in a shell, this keeps signals meant for this
+    /// process, like the `SIGINT` from pressing Ctrl-C, from reaching
+    /// the commands, and lets `RunningPipeline::signal` reach every
+    /// one of them along with anything they start. Commands in a group
+    /// of their own are stopped when they read from the terminal, so
+    /// by default they only get one when the first command's stdin
+    /// isn't the terminal. A command that opens the terminal itself,
+    /// like `ssh` asking for a password, needs this to be `false`.
+    pub fn own_process_group(mut self, own: bool) -> Pipe {
+        self.own_group = Some(own);
+        self
     }
 
     /// This can be used take a peek at the stdout for the current pipe.
-    pub fn peek(&self) -> Result<&ChildStdout> {
-        if let Ok(child) = &self.child {
-            if let Some(ref stdout) = child.stdout {
-                return Ok(stdout);
-            }
+    ///
+    /// Every command chained so far is spawned in order to do so.
+    /// Commands chained after a peek read from whatever is left
+    /// in this stdout. If the final stage is a closure from `then_fn`
+    /// there's no `ChildStdout` to peek at, so `PipeError::NotAProcess`
+    /// is returned.
+    pub fn peek(&mut self) -> Result<&ChildStdout> {
+        if let Err(e) = self.spawn_stages(Target::Piped) {
+            let peeked = e.duplicate();
+            self.stages = Err(e);
+            return Err(peeked);
+        }
+
+        let stage = self.pipeline.running.len() - 1;
+        match self.pipeline.running.last() {
+            Some(&Running {
+                process: Process::Worker(_),
+                ..
+            }) => Err(PipeError::NotAProcess { stage }),
+            Some(&Running {
+                stdout: Some(StageStdout::Child(ref stdout)),
+                ..
+            }) => Ok(stdout),
+            _ => Err(PipeError::MissingStdout { stage }),
         }
-        Err(Error::new(ErrorKind::Other, "No stdout for a command"))
     }
 
     /// Return the `Child` process of the final command that
     /// had data piped into it.
-    pub fn finally(self) -> Result<Child> {
-        self.child
+    ///
+    /// Only the final command is returned, so the commands before
+    /// it are left running and never waited on. Use `spawn` to keep
+    /// hold of every one of them instead. If the final stage is a
+    /// closure from `then_fn` there's no `Child` to return, so
+    /// `PipeError::NotAProcess` is returned.
+    pub fn finally(mut self) -> Result<Child> {
+        self.spawn_stages(Target::Piped)?;
+        self.pipeline.detach();
+        let stage = self.pipeline.running.len().saturating_sub(1);
+        match self.pipeline.running.pop() {
+            Some(Running {
+                process: Process::Child(mut child),
+                stdout,
+                ..
+            }) => {
+                if let Some(StageStdout::Child(stdout)) = stdout {
+                    child.stdout = Some(stdout);
+                }
+                Ok(child)
+            }
+            Some(_) => Err(PipeError::NotAProcess { stage }),
+            None => Err(PipeError::EmptyCommand),
+        }
+    }
+
+    /// Spawn every command and return a handle that owns all of them,
+    /// which the stdout of the final command can be read from.
+    ///
+    /// Unlike `finally`, none of the commands are left behind if the
+    /// handle is dropped before they're waited on. They're killed by
+    /// default, which can be changed with `RunningPipeline::on_drop`.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    /// use std::io::Read;
+    ///
+    /// let mut running = Pipe::new("yes").then("head -n 2").spawn().expect("Commands did not spawn");
+    /// let mut first = [0; 2];
+    /// running.read_exact(&mut first).unwrap();
+    /// assert_eq!(b"y\n", &first);
+    ///
+    /// let out = running.wait().expect("Commands did not pipe");
+    /// assert_eq!(b"y\n", &out.stdout[..]);
+    /// ```
+    pub fn spawn(mut self) -> Result<RunningPipeline> {
+        self.spawn_stages(Target::Piped)?;
+        self.pipeline.watch();
+        Ok(self.pipeline)
+    }
+
+    /// Spawn every command and return an iterator over the lines the
+    /// final command writes, as soon as it writes them.
+    ///
+    /// Every command is waited on once the final one closes its stdout.
+    /// If the pipe failed according to its `SuccessPolicy`, or couldn't
+    /// be spawned at all, the last item is an `io::Error` holding the
+    /// `PipeError`, which is `PipeError::TimedOut` if a timeout set with
+    /// `timeout` ran out while the lines were being read.
+    ///
+    /// ```rust
+    /// use pipers::{Pipe, PipeError};
+    ///
+    /// let mut lines = Pipe::new("printf 'a\nb\n'").then("sh -c 'cat; exit 1'").lines();
+    /// assert_eq!("a", lines.next().unwrap().unwrap());
+    /// assert_eq!("b", lines.next().unwrap().unwrap());
+    ///
+    /// let error = lines.next().unwrap().unwrap_err();
+    /// match error.get_ref().and_then(|e| e.downcast_ref::<PipeError>()) {
+    ///     Some(&PipeError::StageFailed { stage, .. }) => assert_eq!(1, stage),
+    ///     _ => panic!("the last stage should have failed"),
+    /// }
+    /// assert!(lines.next().is_none());
+    /// ```
+    pub fn lines(self) -> Lines {
+        Lines::new(self.split(b'\n'))
+    }
+
+    /// Spawn every command and return an iterator over what the final
+    /// command writes, split on every `delimiter`, like `b'\0'` for
+    /// the output of `find -print0`. This works just like `lines`,
+    /// with the delimiter removed from the end of each item.
+    pub fn split(self, delimiter: u8) -> Split {
+        Split::new(self.spawn(), delimiter)
+    }
+
+    /// Run the pipe until every command has exited, collecting the
+    /// stdout of the final command along with the exit status of
+    /// every command in the pipe.
+    ///
+    /// An error is returned naming the first command that failed
+    /// according to the pipe's `SuccessPolicy`.
+    pub fn output(self) -> Result<PipelineOutput> {
+        self.spawn()?.wait()
+    }
+
+    /// Run the pipe until every command has exited, returning the
+    /// exit status of every command in the pipe.
+    ///
+    /// The final command writes straight to the stdout of this
+    /// process, so the returned `stdout` is always empty. An error
+    /// is returned naming the first command that failed according
+    /// to the pipe's `SuccessPolicy`.
+    pub fn wait_all(mut self) -> Result<PipelineOutput> {
+        self.spawn_stages(Target::Inherit)?;
+        self.pipeline
+            .wait_into(io::stdout())
+            .map(|(output, _)| output)
+    }
+
+    /// Run the pipe with the stdout of the final command going straight
+    /// into the file at `path`, like `> path` or `>> path` in a shell,
+    /// until every command has exited.
+    ///
+    /// The returned `stdout` is always empty. An error is returned if
+    /// the file can't be opened, in which case nothing is spawned, or
+    /// naming the first command that failed according to the pipe's
+    /// `SuccessPolicy`.
+    pub fn finally_to_file<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        mode: FileMode,
+    ) -> Result<PipelineOutput> {
+        let file = mode.open(path.as_ref())?;
+        let copy = file.try_clone()?;
+        self.spawn_stages(Target::File(file))?;
+        self.pipeline.wait_into(copy).map(|(output, _)| output)
+    }
+
+    /// Run the pipe with the stdout of the final command copied into
+    /// `writer` as it's written, until every command has exited. This
+    /// can be anything that implements `Write`, like a socket, a hasher
+    /// or a `&mut Vec<u8>`.
+    ///
+    /// The exit status of every command is returned along with how
+    /// many bytes were copied. An error is returned if writing fails,
+    /// in which case the final command is left without anything
+    /// reading its stdout, or naming the first command that failed
+    /// according to the pipe's `SuccessPolicy`.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    ///
+    /// let mut out = Vec::new();
+    /// let sink = Pipe::new("echo hello")
+    ///     .finally_to_writer(&mut out)
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(6, sink.bytes);
+    /// assert_eq!(b"hello\n", &out[..]);
+    /// ```
+    pub fn finally_to_writer<W: Write + Send>(mut self, writer: W) -> Result<SinkOutput> {
+        self.spawn_stages(Target::Piped)?;
+        let (output, bytes) = self.pipeline.wait_into(writer)?;
+        Ok(SinkOutput { output, bytes })
+    }
+
+    /// Run the pipe with every chunk of the stdout of the final command
+    /// handed to `f` as soon as it's read, until every command has
+    /// exited. An error returned by `f` stops the copying and is
+    /// returned once the commands have exited, just like with
+    /// `finally_to_writer`.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    ///
+    /// let mut lines = 0;
+    /// let sink = Pipe::new("printf 'a\nb\n'")
+    ///     .finally_to_fn(|chunk| {
+    ///         lines += chunk.iter().filter(|&&b| b == b'\n').count();
+    ///         Ok(())
+    ///     })
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!((2, 4), (lines, sink.bytes));
+    /// ```
+    pub fn finally_to_fn<F>(self, f: F) -> Result<SinkOutput>
+    where
+        F: FnMut(&[u8]) -> io::Result<()> + Send,
+    {
+        self.finally_to_writer(sink::Chunks(f))
+    }
+
+    /// Run the pipe with the stdout of the final command copied into
+    /// every one of `branches`, like `tee >(gzip > a.gz) | sha256sum`
+    /// in a shell, until every command in every pipe has exited.
+    ///
+    /// Each branch reads the same data as its stdin, replacing any input
+    /// it was given, and is run as a pipe of its own with its own
+    /// `SuccessPolicy` and timeout. The data is handed to the branches
+    /// at the pace of the slowest one. A branch that stops reading is
+    /// left out from then on, and once every branch has, the final
+    /// command is left to be killed by `SIGPIPE`.
+    ///
+    /// An error is returned if this pipe fails, or else naming the first
+    /// branch that did with `PipeError::BranchFailed`.
+    ///
+    /// ```rust
+    /// use pipers::Pipe;
+    ///
+    /// let out = Pipe::new("printf 'b\na\n'")
+    ///     .fan_out(vec![Pipe::new("sort"), Pipe::new("wc -l")])
+    ///     .expect("Commands did not pipe");
+    /// assert_eq!(b"a\nb\n", &out.branches[0].stdout[..]);
+    /// assert_eq!(b"2", out.branches[1].stdout.trim_ascii());
+    /// ```
+    pub fn fan_out<I: IntoIterator<Item = Pipe>>(self, branches: I) -> Result<FanOutOutput> {
+        // If anything fails to spawn, dropping what was already
+        // spawned kills it
+        let mut running = Vec::new();
+        let mut writers = Vec::new();
+        for (index, mut branch) in branches.into_iter().enumerate() {
+            let (reader, writer) = io::pipe()?;
+            branch.input = Some(Input::Pipe(reader));
+            match branch.spawn() {
+                Ok(branch) => running.push(branch),
+                Err(e) => {
+                    return Err(PipeError::BranchFailed {
+                        branch: index,
+                        source: Box::new(e),
+                    })
+                }
+            }
+            writers.push(writer);
+        }
+        let mut trunk = self.spawn()?;
+        let stdout = trunk.running.last_mut().and_then(|r| r.stdout.take());
+
+        let (trunk, branches, copied) = thread::scope(|scope| {
+            let copying = scope.spawn(move || match stdout {
+                Some(stdout) => broadcast::broadcast(stdout, writers),
+                None => Ok(()),
+            });
+            let waiting: Vec<_> = running
+                .into_iter()
+                .map(|branch| scope.spawn(move || branch.wait()))
+                .collect();
+
+            let trunk = trunk.wait();
+            let branches: Vec<_> = waiting.into_iter().map(|b| joined(b.join())).collect();
+            (trunk, branches, joined(copying.join()))
+        });
+
+        let trunk = trunk?;
+        copied?;
+        let branches = branches
+            .into_iter()
+            .enumerate()
+            .map(|(index, branch)| {
+                branch.map_err(|e| PipeError::BranchFailed {
+                    branch: index,
+                    source: Box::new(e),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(FanOutOutput { trunk, branches })
+    }
+
+    /// Spawn every command that isn't running yet, piping each one
+    /// into the next and sending the stdout of the final one to `target`.
+    ///
+    /// If a command fails to spawn, the ones that already started
+    /// are killed so that none of them are left behind.
+    fn spawn_stages(&mut self, target: Target) -> Result<()> {
+        let stages = mem::replace(&mut self.stages, Ok(Vec::new()))?;
+        let stages = match self.expand_stages(stages) {
+            Ok(stages) => stages,
+            Err(e) => {
+                self.pipeline.kill_running();
+                return Err(e);
+            }
+        };
+        let last = stages.len().saturating_sub(1);
+        if self.pipeline.deadline.is_none() {
+            let timeout = self.pipeline.timeout;
+            self.pipeline.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        }
+        let mut target = Some(target);
+
+        for (index, stage) in stages.into_iter().enumerate() {
+            let stdout = match target.take() {
+                Some(target) if index == last => target,
+                other => {
+                    target = other;
+                    Target::Piped
+                }
+            };
+
+            if let Err(e) = self.spawn_stage(stage, stdout) {
+                self.pipeline.kill_running();
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turn the words of every stage into the command they run, so
+    /// that words that can't be expanded, like a glob pattern without
+    /// matches under `NoMatch::Error`, fail before anything is spawned.
+    fn expand_stages(&self, stages: Vec<Stage>) -> Result<Vec<Stage>> {
+        let first = self.pipeline.running.len();
+        let variables = self.variables.as_ref();
+        let mut expanded = Vec::new();
+        for (index, stage) in stages.into_iter().enumerate() {
+            let run = match stage.run {
+                Run::Words(words) => {
+                    let dir = stage.environment.dir(&self.defaults);
+                    let mut args =
+                        expand::args(first + index, &words, variables, self.globs, dir.as_deref())?
+                            .into_iter();
+                    let mut command = match args.next() {
+                        Some(program) => Command::new(program),
+                        None => return Err(PipeError::EmptyCommand),
+                    };
+                    command.args(args);
+                    Run::Command(command)
+                }
+                run => run,
+            };
+            expanded.push(Stage { run, ..stage });
+        }
+        Ok(expanded)
+    }
+
+    /// Spawn a single command reading from the stdout of the one
+    /// before it, or from the pipe's input if it's the first.
+    fn spawn_stage(&mut self, stage: Stage, stdout: Target) -> Result<()> {
+        let index = self.pipeline.running.len();
+        let previous = self.pipeline.running.last_mut().map(|r| r.stdout.take());
+        let stage = match stage.run {
+            Run::Fn(name, f) => return self.spawn_fn(name, f, stdout, index, previous),
+            run => Stage { run, ..stage },
+        };
+        let Prepared {
+            mut command,
+            source,
+            reader,
+        } = self.prepare_stage(stage, stdout, index, previous)?;
+
+        // The `Command` is dropped right after spawning so that it
+        // doesn't hold on to the ends of the pipes it was given.
+        let mut child = command
+            .spawn()
+            .map_err(|source| spawn_failed(index, &command, source))?;
+
+        self.spawned(child.id());
+        if let (Some(source), Some(stdin)) = (source, child.stdin.take()) {
+            self.pipeline.feeder = Some(input::feed(source, stdin));
+        }
+        let stdout = match (reader, child.stdout.take()) {
+            (Some(reader), _) => StageStdout::Pipe(reader),
+            (None, Some(stdout)) => StageStdout::Child(stdout),
+            (None, None) => StageStdout::Redirected,
+        };
+        let limit = self.stderr_limit;
+        let stderr = child.stderr.take().map(|err| capture::capture(err, limit));
+
+        self.pipeline.running.push(Running {
+            command: command_line(&command),
+            process: Process::Child(child),
+            stdout: Some(stdout),
+            stderr,
+        });
+        Ok(())
+    }
+
+    /// Start a closure on a thread of its own as the stage at `index`,
+    /// reading from `previous` just like a command would.
+    fn spawn_fn(
+        &mut self,
+        name: String,
+        f: Box<StageFn>,
+        stdout: Target,
+        index: usize,
+        previous: Option<Option<StageStdout>>,
+    ) -> Result<()> {
+        let reader: Box<dyn Read + Send> = match previous {
+            Some(Some(stdin)) => Box::new(stdin),
+            Some(None) => return Err(PipeError::MissingStdout { stage: index - 1 }),
+            None => match self.input.take() {
+                Some(input) => input.into_reader()?,
+                None => Box::new(io::stdin()),
+            },
+        };
+        let (writer, stdout): (Box<dyn Write + Send>, _) = match stdout {
+            Target::Piped => {
+                let (reader, writer) = io::pipe()?;
+                (Box::new(writer), StageStdout::Pipe(reader))
+            }
+            Target::Inherit => (Box::new(io::stdout()), StageStdout::Redirected),
+            Target::File(file) => (Box::new(file), StageStdout::Redirected),
+        };
+
+        self.pipeline.running.push(Running {
+            command: name,
+            process: Process::Worker(function::spawn(f, reader, writer)),
+            stdout: Some(stdout),
+            stderr: None,
+        });
+        Ok(())
+    }
+
+    /// Set up the command for the stage at `index` and wire up where it
+    /// reads and writes, without spawning it yet.
+    ///
+    /// `previous` is the stdout of the stage before it, which is `None`
+    /// for the first stage and `Some(None)` if something else already
+    /// took it.
+    fn prepare_stage(
+        &mut self,
+        stage: Stage,
+        stdout: Target,
+        index: usize,
+        previous: Option<Option<StageStdout>>,
+    ) -> Result<Prepared> {
+        let variables = self.variables.as_ref();
+        let mut command = match stage.run {
+            Run::Command(command) => command,
+            Run::Fn(..) => unreachable!("closures are started by `spawn_fn`"),
+            Run::Words(_) => unreachable!("words are expanded by `expand_stages`"),
+        };
+
+        stage.environment.apply(&self.defaults, &mut command);
+
+        let dir = command.get_current_dir().map(Path::to_path_buf);
+        let (mut stdin, mut stderr) = (None, None);
+        let mut redirected: Option<(PathBuf, FileMode)> = None;
+        // Whether `stderr` goes where the stdout would have gone without
+        // its redirection, since `2>&1` came before it
+        let mut merged_first = false;
+        for redirect in stage.redirects {
+            let path = |word: &Word| -> Result<PathBuf> {
+                let path = PathBuf::from(expand::resolve(word, variables)?.unwrap_or_default());
+                Ok(match dir {
+                    Some(ref dir) => dir.join(path),
+                    None => path,
+                })
+            };
+            match redirect {
+                Redirect::Stdin(word) => stdin = Some(path(&word)?),
+                Redirect::Stdout(word, mode) => {
+                    // An earlier `2>&1` keeps pointing at the old stdout
+                    if stderr == Some(Stderr::Merge) && !merged_first {
+                        match redirected {
+                            Some((ref path, mode)) => {
+                                stderr = Some(Stderr::File(path.clone(), mode))
+                            }
+                            None => merged_first = true,
+                        }
+                    }
+                    redirected = Some((path(&word)?, mode));
+                }
+                Redirect::Stderr(word, mode) => {
+                    stderr = Some(Stderr::File(path(&word)?, mode));
+                    merged_first = false;
+                }
+                Redirect::StderrToStdout => {
+                    stderr = Some(Stderr::Merge);
+                    merged_first = false;
+                }
+                Redirect::Both(word, mode) => {
+                    redirected = Some((path(&word)?, mode));
+                    stderr = Some(Stderr::Merge);
+                    merged_first = false;
+                }
+            }
+        }
+        merged_first &= stage.stderr.is_none();
+        // A file given to `Pipe::stderr` is found the same way as one
+        // written in the command string
+        let stderr = match (stage.stderr, dir) {
+            (Some(Stderr::File(path, mode)), Some(ref dir)) => {
+                Some(Stderr::File(dir.join(path), mode))
+            }
+            (Some(stage), _) => Some(stage),
+            (None, _) => stderr,
+        };
+
+        // Left with the stdin of this process, which may be a terminal
+        let reads_terminal = stdin.is_none()
+            && previous.is_none()
+            && self.input.is_none()
+            && io::stdin().is_terminal();
+        let mut source = None;
+        if let Some(path) = stdin {
+            // Nothing reads from the command before this one anymore,
+            // so `previous` is dropped along the way
+            self.input = None;
+            match File::open(&path) {
+                Ok(file) => command.stdin(file),
+                Err(source) => return Err(PipeError::OpenFailed { path, source }),
+            };
+        } else if let Some(previous) = previous {
+            match previous {
+                Some(stdin) => command.stdin(Stdio::from(stdin)),
+                None => return Err(PipeError::MissingStdout { stage: index - 1 }),
+            };
+        } else if let Some(input) = self.input.take() {
+            let (stdin, data) = input.into_stdio()?;
+            command.stdin(stdin);
+            source = data;
+        }
+
+        let stderr = stderr.or(if self.capture_stderr {
+            Some(Stderr::Capture)
+        } else {
+            None
+        });
+        let reader = match redirected {
+            Some((path, mode)) if merged_first => {
+                let reader = redirect::wire(&mut command, stdout, Some(&Stderr::Merge))?;
+                command.stdout(mode.open(&path)?);
+                reader
+            }
+            Some((path, mode)) => redirect::wire(
+                &mut command,
+                Target::File(mode.open(&path)?),
+                stderr.as_ref(),
+            )?,
+            None => redirect::wire(&mut command, stdout, stderr.as_ref())?,
+        };
+        // The first command decides whether there's a group, starting
+        // it if there is, and the rest join it
+        if *self.own_group.get_or_insert(!reads_terminal) {
+            command.process_group(self.pipeline.group.map_or(0, Pid::as_raw));
+        }
+
+        Ok(Prepared {
+            command,
+            source,
+            reader,
+        })
+    }
+
+    /// Keep track of the process group started by the first command.
+    fn spawned(&mut self, id: u32) {
+        if self.own_group == Some(true) && self.pipeline.group.is_none() {
+            self.pipeline.group = Some(Pid::from_raw(id as i32));
+        }
     }
 }
 
-/// Helper method to generate a new error from a string
-/// but have it be a `Pipe` so that it can be passed through
-/// the chain.
-fn pipe_new_error(error: &str) -> Pipe {
-    Pipe {
-        child: Err(Error::new(ErrorKind::Other, error)),
+/// A command that has its stdin, stdout and stderr wired up and
+/// is ready to be spawned.
+struct Prepared {
+    command: Command,
+    /// What to write into the stdin of the command once it's spawned.
+    source: Option<Box<dyn Read + Send>>,
+    /// The read end of the pipe the command writes its stdout to,
+    /// if it shares that pipe with its stderr.
+    reader: Option<PipeReader>,
+}
+
+/// Helper method to create the error for a command that couldn't
+/// be spawned as the stage at `index`.
+fn spawn_failed(index: usize, command: &Command, source: io::Error) -> PipeError {
+    PipeError::SpawnFailed {
+        stage: index,
+        program: command.get_program().to_string_lossy().into_owned(),
+        source,
+    }
+}
+
+/// Helper method to split a command string into the `Stage`
+/// running it.
+fn stage_from_str(command: &str) -> Result<Stage> {
+    parse::split(command)
+        .map_err(PipeError::from)
+        .and_then(stage_from_parsed)
+}
+
+/// Helper method to turn a parsed command into a `Stage` that runs
+/// its words with its redirections applied.
+fn stage_from_parsed(parsed: Parsed) -> Result<Stage> {
+    if parsed.words.is_empty() {
+        return Err(PipeError::EmptyCommand);
+    }
+
+    Ok(Stage {
+        run: Run::Words(parsed.words),
+        redirects: parsed.redirects,
+        stderr: None,
+        environment: Environment::default(),
+    })
+}
+
+/// Helper method to make a `Stage` running the closure `f`, which
+/// is called `name` in its `StageOutput`.
+fn stage_from_fn(name: &str, f: Box<StageFn>) -> Stage {
+    Stage {
+        run: Run::Fn(name.to_string(), f),
+        redirects: Vec::new(),
+        stderr: None,
+        environment: Environment::default(),
+    }
+}
+
+/// Helper method to return what a thread returned, or carry on
+/// with its panic if it panicked.
+fn joined<T>(joined: thread::Result<T>) -> T {
+    match joined {
+        Ok(returned) => returned,
+        Err(panic) => panic::resume_unwind(panic),
     }
 }
 
-/// Helper method used to pass the error down the chain by creating
-/// a new pipe with the error passed in.
-fn pipe_error(error: Result<Child>) -> Pipe {
-    Pipe { child: error }
+/// Helper method to show a command the way it would be typed,
+/// with its program followed by its arguments.
+fn command_line(command: &Command) -> String {
+    let mut line = command.get_program().to_string_lossy().into_owned();
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
 }
 
 #[test]
@@ -103,3 +1257,1060 @@ fn test_pipe() {
 
     assert_eq!("u", &String::from_utf8(out.stdout).unwrap());
 }
+
+#[test]
+fn test_pipe_quoted_args() {
+    let out = Pipe::new("printf '%s\\n' 'hello world' foo")
+        .then("grep 'hello world'")
+        .finally()
+        .expect("Commands did not pipe")
+        .wait_with_output()
+        .expect("failed to wait on child");
+
+    assert_eq!("hello world\n", &String::from_utf8(out.stdout).unwrap());
+}
+
+#[test]
+fn test_pipe_parse_error() {
+    match Pipe::new("echo 'oops").finally() {
+        Err(PipeError::ParseError(ParseError::UnterminatedQuote { position: 5, .. })) => {}
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    match Pipe::new(" ").finally() {
+        Err(PipeError::EmptyCommand) => {}
+        other => panic!("expected an empty command, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_parse() {
+    let out = Pipe::parse("printf 'a|b\\nc\\n' | grep '|' | head -c 1")
+        .finally()
+        .expect("Commands did not pipe")
+        .wait_with_output()
+        .expect("failed to wait on child");
+
+    assert_eq!("a", &String::from_utf8(out.stdout).unwrap());
+
+    let err = Pipe::parse("ls / |").finally().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "failed to parse command: empty pipeline stage at position 6"
+    );
+}
+
+#[test]
+fn test_pipe_command() {
+    let mut printf = Command::new("printf");
+    printf.args(["%s-\\n", "hello world"]);
+    let mut sed = Command::new("sh");
+    sed.args(["-c", "sed \"s/-/ $PIPERS_TEST/\""])
+        .env("PIPERS_TEST", "from");
+
+    let out = Pipe::from_command(printf)
+        .then_command(sed)
+        .then("cat")
+        .finally()
+        .expect("Commands did not pipe")
+        .wait_with_output()
+        .expect("failed to wait on child");
+
+    assert_eq!(
+        "hello world from\n",
+        &String::from_utf8(out.stdout).unwrap()
+    );
+}
+
+#[test]
+fn test_pipe_args() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let out = Pipe::new_args("printf", ["%s\\n", "'a  b' *"])
+        .then_args("grep", vec!["-F", "'a  b' *"])
+        .finally()
+        .expect("Commands did not pipe")
+        .wait_with_output()
+        .expect("failed to wait on child");
+    assert_eq!("'a  b' *\n", &String::from_utf8(out.stdout).unwrap());
+
+    let out = Pipe::new_args("printf", [OsStr::new("%s"), OsStr::from_bytes(b"\xffab")])
+        .then_args("cat", Vec::<&OsStr>::new())
+        .finally()
+        .expect("Commands did not pipe")
+        .wait_with_output()
+        .expect("failed to wait on child");
+    assert_eq!(b"\xffab", &out.stdout[..]);
+}
+
+#[test]
+fn test_pipe_output() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let out = Pipe::new("false")
+        .then("printf 'a\\nb\\n'")
+        .then("grep b")
+        .output()
+        .expect("Commands did not pipe");
+
+    assert_eq!("b\n", &String::from_utf8(out.stdout.clone()).unwrap());
+    assert_eq!(out.stages[1].command, "printf a\\nb\\n");
+    assert_eq!(
+        out.statuses(),
+        vec![
+            ExitStatusExt::from_raw(1 << 8),
+            ExitStatusExt::from_raw(0),
+            ExitStatusExt::from_raw(0)
+        ]
+    );
+
+    let out = Pipe::new("true").then("true").wait_all().unwrap();
+    assert!(out.statuses().iter().all(|status| status.success()));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn test_pipe_peek() {
+    let mut pipe = Pipe::new("printf 'a\\nb\\n'");
+    assert!(pipe.peek().is_ok());
+
+    let out = pipe.then("cat").output().unwrap();
+    assert_eq!(b"a\nb\n", &out.stdout[..]);
+    assert_eq!(out.stages.len(), 2);
+
+    let mut pipe = Pipe::new("echo a").then_fn(|input, output| {
+        io::copy(input, output)?;
+        Ok(())
+    });
+    match pipe.peek() {
+        Err(PipeError::NotAProcess { stage }) => assert_eq!(1, stage),
+        other => panic!("expected NotAProcess, got {:?}", other.map(|_| ())),
+    }
+    let out = pipe.output().unwrap();
+    assert_eq!(b"a\n", &out.stdout[..]);
+}
+
+#[test]
+fn test_pipe_spawn_error() {
+    let err = Pipe::new("sleep 5")
+        .then("pipers-does-not-exist")
+        .output()
+        .unwrap_err();
+    match err {
+        PipeError::SpawnFailed {
+            stage: 1,
+            ref program,
+            ref source,
+        } => {
+            assert_eq!(program, "pipers-does-not-exist");
+            assert_eq!(source.kind(), io::ErrorKind::NotFound);
+        }
+        other => panic!("expected a spawn failure, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_policy() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let err = Pipe::new("printf a").then("grep b").output().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "stage 1 (`grep b`) failed with exit status: 1"
+    );
+
+    assert!(Pipe::new("false").then("true").output().is_ok());
+    let err = Pipe::new("false")
+        .then("true")
+        .policy(SuccessPolicy::Pipefail)
+        .wait_all()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "stage 0 (`false`) failed with exit status: 1"
+    );
+
+    let out = Pipe::new("yes")
+        .then("head -n 1")
+        .policy(SuccessPolicy::custom(|_, stage| {
+            stage.status.success() || stage.status.signal() == Some(13)
+        }))
+        .output()
+        .unwrap();
+    assert_eq!(b"y\n", &out.stdout[..]);
+}
+
+#[test]
+fn test_pipe_concurrent_stress() {
+    use std::thread;
+
+    // Every stage hands its stdout to the next one by moving it, so
+    // no descriptor can be closed twice or end up in the wrong pipeline
+    // while other threads are opening and closing their own.
+    let threads = (0..16)
+        .map(|thread| {
+            thread::spawn(move || {
+                for run in 0..25 {
+                    let token = format!("{}-{}", thread, run);
+                    let out = Pipe::new_args("printf", ["%s\\n", &token])
+                        .then("cat")
+                        .then("cat")
+                        .output()
+                        .expect("Commands did not pipe");
+                    assert_eq!(format!("{}\n", token).as_bytes(), &out.stdout[..]);
+                    assert_eq!(out.stages.len(), 3);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn test_pipe_input() {
+    use std::env;
+    use std::fs;
+
+    // Larger than any pipe buffer, so writing it all before reading
+    // the output would never finish.
+    let input = vec![b'a'; 1 << 20];
+    let out = Pipe::new("cat")
+        .then("cat")
+        .with_input(&input[..])
+        .output()
+        .unwrap();
+    assert_eq!(input, out.stdout);
+
+    let out = Pipe::new("head -c 3")
+        .stdin_reader(io::repeat(b'b').take(1 << 20))
+        .output()
+        .unwrap();
+    assert_eq!(b"bbb", &out.stdout[..]);
+
+    let path = env::temp_dir().join(format!("pipers-input-{}", std::process::id()));
+    fs::write(&path, "one\ntwo\n").unwrap();
+    let out = Pipe::new("grep two").stdin_file(&path).output().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(b"two\n", &out.stdout[..]);
+
+    match Pipe::new("cat").stdin_file(&path).output() {
+        Err(PipeError::OpenFailed {
+            path: ref missing, ..
+        }) => assert_eq!(missing, &path),
+        other => panic!("expected an open failure, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_capture_stderr() {
+    let out = Pipe::new("sh -c 'echo one >&2; echo out'")
+        .then("sh -c 'cat; echo two >&2'")
+        .capture_stderr()
+        .output()
+        .unwrap();
+    assert_eq!(b"out\n", &out.stdout[..]);
+    assert_eq!(b"one\n", &out.stages[0].stderr[..]);
+    assert_eq!(b"two\n", &out.stages[1].stderr[..]);
+
+    // Far more than a pipe can buffer, so it has to be drained
+    // while the stage is still running.
+    let out = Pipe::new("sh -c 'head -c 1000000 /dev/zero >&2; echo done'")
+        .capture_stderr()
+        .stderr_limit(10)
+        .output()
+        .unwrap();
+    assert_eq!(b"done\n", &out.stdout[..]);
+    assert_eq!(vec![0; 10], out.stages[0].stderr);
+
+    match Pipe::new("sh -c 'echo oops >&2; exit 3'")
+        .capture_stderr()
+        .output()
+    {
+        Err(PipeError::StageFailed { ref stderr, .. }) => assert_eq!(b"oops\n", &stderr[..]),
+        other => panic!("expected the stage to fail, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_stderr_redirect() {
+    use std::env;
+    use std::fs;
+
+    let out = Pipe::new("sh -c 'echo out; echo err >&2'")
+        .stderr(Stderr::Merge)
+        .then("sort")
+        .output()
+        .unwrap();
+    assert_eq!(b"err\nout\n", &out.stdout[..]);
+
+    let out = Pipe::new("sh -c 'echo out; echo err >&2'")
+        .stderr(Stderr::Merge)
+        .output()
+        .unwrap();
+    assert_eq!(b"out\nerr\n", &out.stdout[..]);
+
+    let out = Pipe::new("sh -c 'echo one >&2'")
+        .stderr(Stderr::Null)
+        .then("sh -c 'echo two >&2'")
+        .capture_stderr()
+        .output()
+        .unwrap();
+    assert!(out.stages[0].stderr.is_empty());
+    assert_eq!(b"two\n", &out.stages[1].stderr[..]);
+
+    let path = env::temp_dir().join(format!("pipers-stderr-{}", std::process::id()));
+    for mode in &[FileMode::Truncate, FileMode::Append, FileMode::Append] {
+        Pipe::new("sh -c 'echo err >&2'")
+            .stderr(Stderr::File(path.clone(), *mode))
+            .output()
+            .unwrap();
+    }
+    let written = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!("err\nerr\nerr\n", written);
+
+    // The stderr a command was set up with is kept
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo err >&2"]).stderr(Stdio::null());
+    let out = Pipe::from_command(command).output().unwrap();
+    assert!(out.stages[0].stderr.is_empty());
+
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo err >&2"]).stderr(Stdio::piped());
+    let out = Pipe::from_command(command).output().unwrap();
+    assert_eq!(b"err\n", &out.stages[0].stderr[..]);
+}
+
+#[test]
+fn test_pipe_finally_to_file() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("pipers-stdout-{}", std::process::id()));
+    let out = Pipe::new("printf 'b\\na\\n'")
+        .then("sort")
+        .finally_to_file(&path, FileMode::Truncate)
+        .unwrap();
+    assert!(out.stdout.is_empty());
+    assert_eq!(out.stages.len(), 2);
+
+    Pipe::new("echo c")
+        .finally_to_file(&path, FileMode::Append)
+        .unwrap();
+    let written = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!("a\nb\nc\n", written);
+
+    let missing = path.join("missing");
+    match Pipe::new("echo c").finally_to_file(&missing, FileMode::Truncate) {
+        Err(PipeError::OpenFailed { ref path, .. }) => assert_eq!(path, &missing),
+        other => panic!("expected an open failure, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_parsed_redirects() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir();
+    let input = dir.join(format!("pipers-redirect-in-{}", std::process::id()));
+    let output = dir.join(format!("pipers-redirect-out-{}", std::process::id()));
+    fs::write(&input, "b\na\n").unwrap();
+
+    let out = Pipe::parse(&format!(
+        "sort < {} | tr a-z A-Z >{} 2>/dev/null",
+        input.display(),
+        output.display()
+    ))
+    .output()
+    .unwrap();
+    assert!(out.stdout.is_empty());
+    assert_eq!("A\nB\n", fs::read_to_string(&output).unwrap());
+
+    Pipe::new(&format!(
+        "sh -c 'echo out; echo err >&2' &>> {}",
+        output.display()
+    ))
+    .output()
+    .unwrap();
+    assert_eq!("A\nB\nout\nerr\n", fs::read_to_string(&output).unwrap());
+
+    // The stage after a redirected stdout reads nothing
+    let out = Pipe::new(&format!("echo hi > {}", output.display()))
+        .then("wc -c")
+        .output()
+        .unwrap();
+    assert_eq!("0", String::from_utf8(out.stdout).unwrap().trim());
+    assert_eq!("hi\n", fs::read_to_string(&output).unwrap());
+
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+
+    let out = Pipe::new("sh -c 'echo err >&2' 2>&1")
+        .then("cat")
+        .output()
+        .unwrap();
+    assert_eq!(b"err\n", &out.stdout[..]);
+
+    match Pipe::new("cat <<EOF").output() {
+        Err(PipeError::ParseError(ParseError::UnsupportedRedirect { .. })) => {}
+        other => panic!("expected an unsupported redirection, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_redirect_order() {
+    use std::env;
+    use std::fs;
+
+    let both = "sh -c 'echo out; echo err >&2'";
+    let out = Pipe::new(&format!("{} 2>&1 >/dev/null", both))
+        .then("cat")
+        .output()
+        .unwrap();
+    assert_eq!(b"err\n", &out.stdout[..]);
+
+    let dir = env::temp_dir();
+    let first = dir.join(format!("pipers-order-first-{}", std::process::id()));
+    let second = dir.join(format!("pipers-order-second-{}", std::process::id()));
+    let out = Pipe::new(&format!("{} >{} 2>&1", both, first.display()))
+        .then("wc -c")
+        .output()
+        .unwrap();
+    assert_eq!(b"0", out.stdout.trim_ascii());
+    assert_eq!("out\nerr\n", fs::read_to_string(&first).unwrap());
+
+    // `2>&1` keeps the file the stdout went to before it was moved again
+    Pipe::new(&format!(
+        "{} >{} 2>&1 >{}",
+        both,
+        first.display(),
+        second.display()
+    ))
+    .output()
+    .unwrap();
+    assert_eq!("err\n", fs::read_to_string(&first).unwrap());
+    assert_eq!("out\n", fs::read_to_string(&second).unwrap());
+
+    fs::remove_file(&first).unwrap();
+    fs::remove_file(&second).unwrap();
+}
+
+#[test]
+fn test_pipe_expand() {
+    use std::env;
+    use std::fs;
+
+    let vars = [("X", "a b"), ("HOME", "/home/pipers"), ("EMPTY", "")];
+    let out = Pipe::new(r#"printf '[%s]' $X '$X' "${Y:-d}" ~/bin $EMPTY "$EMPTY" \~"#)
+        .expand_with(vars.iter().cloned())
+        .output()
+        .unwrap();
+    assert_eq!(
+        "[a b][$X][d][/home/pipers/bin][][~]",
+        String::from_utf8(out.stdout).unwrap()
+    );
+
+    // Without opting in, everything is passed along as it was written
+    let out = Pipe::new("echo $HOME ~").output().unwrap();
+    assert_eq!("$HOME ~\n", String::from_utf8(out.stdout).unwrap());
+
+    let path = env::temp_dir().join(format!("pipers-expand-{}", std::process::id()));
+    Pipe::new("echo hi > $OUT")
+        .expand_with(vec![("OUT", path.clone())])
+        .wait_all()
+        .unwrap();
+    assert_eq!("hi\n", fs::read_to_string(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+
+    match Pipe::new("$PIPERS_UNSET").expand_env().output() {
+        Err(PipeError::EmptyCommand) => {}
+        other => panic!("expected an empty command, got {:?}", other.map(|_| ())),
+    }
+    // Forms that can't be expanded are left alone unless expanding
+    let out = Pipe::new(r#"sh -c "A=xb; echo ${A%b}""#).output().unwrap();
+    assert_eq!(b"x\n", &out.stdout[..]);
+    let out = Pipe::new(r#"echo ${#} "${1:?x}""#).output().unwrap();
+    assert_eq!(b"${#} ${1:?x}\n", &out.stdout[..]);
+    match Pipe::new("echo ${A%b}").expand_env().output() {
+        Err(PipeError::ParseError(ParseError::BadSubstitution { position: 5 })) => {}
+        other => panic!("expected a bad substitution, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_pipe_glob() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("pipers-glob-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub/deep")).unwrap();
+    for file in &[
+        "b.rs",
+        "a.rs",
+        ".hidden.rs",
+        "c.txt",
+        "sub/d.rs",
+        "sub/deep/e.rs",
+    ] {
+        fs::write(dir.join(file), "").unwrap();
+    }
+    let dir = dir.to_str().unwrap();
+    let names = |out: PipelineOutput| {
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .replace(dir, "")
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+
+    let out = Pipe::new(&format!(
+        "printf '%s\\n' {0}/*.rs '{0}/*.rs' {0}/[bc].* {0}/?.txt",
+        dir
+    ))
+    .glob(NoMatch::Keep)
+    .output()
+    .unwrap();
+    assert_eq!(
+        names(out),
+        vec!["/a.rs", "/b.rs", "/*.rs", "/b.rs", "/c.txt", "/c.txt"]
+    );
+
+    let out = Pipe::new(&format!("printf '%s\\n' {}/**/*.rs", dir))
+        .glob(NoMatch::Keep)
+        .output()
+        .unwrap();
+    assert_eq!(
+        names(out),
+        vec!["/a.rs", "/b.rs", "/sub/d.rs", "/sub/deep/e.rs"]
+    );
+
+    let missing = format!("echo {}/*.md end", dir);
+    let out = Pipe::new(&missing).glob(NoMatch::Keep).output().unwrap();
+    assert_eq!(names(out), vec!["/*.md end"]);
+    let out = Pipe::new(&missing).glob(NoMatch::Drop).output().unwrap();
+    assert_eq!(names(out), vec!["end"]);
+    // Nothing is spawned, not even the stages before the pattern
+    let spawned = format!("{}/spawned", dir);
+    match Pipe::new(&format!("touch {}", spawned))
+        .then(&missing)
+        .glob(NoMatch::Error)
+        .output()
+    {
+        Err(PipeError::NoMatch { stage: 1, pattern }) => {
+            assert_eq!(format!("{}/*.md", dir), pattern)
+        }
+        other => panic!("expected no matches, got {:?}", other.map(|_| ())),
+    }
+    assert!(!Path::new(&spawned).exists());
+
+    // Without opting in, patterns are passed along as they are
+    let out = Pipe::new(&format!("echo {}/*.rs", dir)).output().unwrap();
+    assert_eq!(names(out), vec!["/*.rs"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_pipe_environment() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("pipers-environment-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.txt"), "").unwrap();
+
+    let out = Pipe::new("sh -c 'echo $A $B $C'")
+        .env("B", "stage")
+        .env_remove("C")
+        .then("sh -c 'cat; echo $A $B $C'")
+        .default_env("A", "default")
+        .default_env("C", "default")
+        .output()
+        .unwrap();
+    assert_eq!(
+        "default stage\ndefault default\n",
+        String::from_utf8(out.stdout).unwrap()
+    );
+
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo $A $B"]).env("A", "own");
+    let out = Pipe::from_command(command)
+        .default_env("A", "default")
+        .output()
+        .unwrap();
+    assert_eq!("own\n", String::from_utf8(out.stdout).unwrap());
+
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo $A $B"]).env("A", "own");
+    let out = Pipe::from_command(command)
+        .env_clear()
+        .env("B", "stage")
+        .then_args("sh", ["-c", "cat; echo ${A:-cleared}"])
+        .default_env("A", "default")
+        .default_env_clear()
+        .output()
+        .unwrap();
+    assert_eq!("stage\ncleared\n", String::from_utf8(out.stdout).unwrap());
+
+    // Relative directories stack, and globs and redirections follow them
+    let out = Pipe::new("ls *.txt")
+        .current_dir("sub")
+        .glob(NoMatch::Error)
+        .then("cat > copy")
+        .default_current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(out.stdout.is_empty());
+    assert_eq!("a.txt\n", fs::read_to_string(dir.join("copy")).unwrap());
+
+    // Either way of sending the stderr to a relative file agrees
+    Pipe::new("sh -c 'echo one >&2' 2>one.err")
+        .then("sh -c 'echo two >&2'")
+        .stderr(Stderr::File("two.err".into(), FileMode::Truncate))
+        .default_current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!("one\n", fs::read_to_string(dir.join("one.err")).unwrap());
+    assert_eq!("two\n", fs::read_to_string(dir.join("two.err")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_pipe_timeout() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let started = Instant::now();
+    match Pipe::new("sh -c 'echo started; exec sleep 10'")
+        .then("cat")
+        .timeout(Duration::from_millis(200))
+        .output()
+    {
+        Err(PipeError::TimedOut { timeout, output }) => {
+            assert_eq!(Duration::from_millis(200), timeout);
+            assert_eq!(b"started\n", &output.stdout[..]);
+            assert_eq!(Some(15), output.stages[0].status.signal());
+            assert_eq!(Some(15), output.stages[1].status.signal());
+        }
+        other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // A command ignoring SIGTERM is killed after the grace period
+    let started = Instant::now();
+    match Pipe::new("sh -c 'trap \"\" TERM; exec sleep 10'")
+        .timeout(Duration::from_millis(100))
+        .grace_period(Duration::from_millis(100))
+        .wait_all()
+    {
+        Err(PipeError::TimedOut { output, .. }) => {
+            assert_eq!(Some(9), output.stages[0].status.signal())
+        }
+        other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // A stage that never closes its stdout doesn't block reading it
+    let started = Instant::now();
+    let mut lines = Pipe::new("sh -c 'echo a; exec sleep 10'")
+        .then("cat")
+        .timeout(Duration::from_millis(100))
+        .lines();
+    assert_eq!("a", lines.next().unwrap().unwrap());
+    let error = lines.next().unwrap().unwrap_err();
+    match error.get_ref().and_then(|e| e.downcast_ref::<PipeError>()) {
+        Some(PipeError::TimedOut { output, .. }) => {
+            assert_eq!(Some(15), output.stages[0].status.signal())
+        }
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(lines.next().is_none());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let out = Pipe::new("echo fast")
+        .timeout(Duration::from_secs(10))
+        .output()
+        .unwrap();
+    assert_eq!(b"fast\n", &out.stdout[..]);
+}
+
+#[test]
+fn test_pipe_running_pipeline() {
+    use nix::sys::signal;
+    use nix::unistd::Pid;
+    use std::env;
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+
+    let exists = |id: u32| signal::kill(Pid::from_raw(id as i32), None).is_ok();
+
+    // Dropping the handle kills and reaps every command
+    let started = Instant::now();
+    let running = Pipe::new("sleep 10").then("cat").spawn().unwrap();
+    let ids = running.ids();
+    assert_eq!(2, ids.len());
+    drop(running);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(!ids.into_iter().any(exists));
+
+    // Or waits for them, closing the final stdout so that nothing blocks
+    let path = env::temp_dir().join(format!("pipers-on-drop-{}", std::process::id()));
+    let running = Pipe::new("yes")
+        .then_args(
+            "sh",
+            ["-c", "sleep 0.1; touch \"$0\"; cat", path.to_str().unwrap()],
+        )
+        .spawn()
+        .unwrap()
+        .on_drop(OnDrop::Wait);
+    let ids = running.ids();
+    drop(running);
+    assert!(path.exists());
+    assert!(!ids.into_iter().any(exists));
+    fs::remove_file(&path).unwrap();
+
+    let mut running = Pipe::new("sleep 10").spawn().unwrap();
+    running.kill().unwrap();
+    match running.wait() {
+        Err(PipeError::StageFailed { status, .. }) => assert_eq!(Some(9), status.signal()),
+        other => panic!("expected a killed stage, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_pipe_process_group() {
+    use std::thread;
+
+    let mut running = Pipe::new("sleep 10")
+        .then("cat")
+        .own_process_group(true)
+        .spawn()
+        .unwrap();
+    let ids = running.ids();
+    assert_eq!(Some(ids[0]), running.group_id());
+    for id in ids {
+        let out = Pipe::new(&format!("ps -o pgid= -p {}", id))
+            .output()
+            .unwrap();
+        let group = String::from_utf8(out.stdout).unwrap();
+        assert_eq!(running.group_id(), group.trim().parse().ok());
+    }
+    running.kill().unwrap();
+    assert!(running.wait().is_err());
+
+    // Signals reach everything in the group, not just the commands
+    let mut running = Pipe::new("sh -c 'sleep 10 & echo $!; wait'")
+        .own_process_group(true)
+        .spawn()
+        .unwrap();
+    let mut line = [0; 32];
+    let read = running.read(&mut line).unwrap();
+    let sleep: i32 = String::from_utf8_lossy(&line[..read])
+        .trim()
+        .parse()
+        .unwrap();
+    running.signal(Signal::SIGTERM).unwrap();
+    assert!(running.wait().is_err());
+    // Whatever adopts the orphaned `sleep` may not reap it right away
+    let alive = || {
+        let out = Pipe::new(&format!("ps -o stat= -p {}", sleep)).output();
+        let stat = out.map(|out| out.stdout).unwrap_or_default();
+        !stat.is_empty() && !stat.starts_with(b"Z")
+    };
+    for _ in 0..100 {
+        if !alive() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!alive());
+
+    let running = Pipe::new("true").own_process_group(false).spawn().unwrap();
+    assert_eq!(None, running.group_id());
+    running.wait().unwrap();
+
+    // Commands that can't read from the terminal get a group by default
+    let running = Pipe::new("cat").with_input("a").spawn().unwrap();
+    assert_eq!(Some(running.ids()[0]), running.group_id());
+    running.wait().unwrap();
+}
+
+#[test]
+fn test_pipe_lines() {
+    let lines: Vec<String> = Pipe::new("printf 'one\\ntwo\\r\\nthree'")
+        .lines()
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(vec!["one", "two", "three"], lines);
+
+    // Lines arrive before the command that writes them exits
+    let started = Instant::now();
+    let mut lines = Pipe::new("sh -c 'echo first; sleep 1; echo second'").lines();
+    assert_eq!("first", lines.next().unwrap().unwrap());
+    assert!(started.elapsed() < Duration::from_millis(900));
+    assert_eq!("second", lines.next().unwrap().unwrap());
+    assert!(lines.next().is_none());
+
+    // The failure comes after everything that was written
+    let mut lines = Pipe::new("echo out").then("sh -c 'cat; exit 3'").lines();
+    assert_eq!("out", lines.next().unwrap().unwrap());
+    let error = lines.next().unwrap().unwrap_err();
+    match error.get_ref().and_then(|e| e.downcast_ref::<PipeError>()) {
+        Some(PipeError::StageFailed { stage, status, .. }) => {
+            assert_eq!(1, *stage);
+            assert_eq!(Some(3), status.code());
+        }
+        other => panic!("expected a failed stage, got {:?}", other),
+    }
+    assert!(lines.next().is_none());
+
+    let mut lines = Pipe::new("").lines();
+    assert!(lines.next().unwrap().is_err());
+    assert!(lines.next().is_none());
+
+    let split: Vec<Vec<u8>> = Pipe::new("printf 'a b\\0c\\0'")
+        .split(b'\0')
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(vec![b"a b".to_vec(), b"c".to_vec()], split);
+}
+
+#[test]
+fn test_pipe_fn() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::ExitStatusExt;
+
+    let out = Pipe::new("printf 'b\\na\\n'")
+        .then_fn(|reader, writer| {
+            for line in BufReader::new(reader).lines() {
+                writeln!(writer, "{}", line?.to_uppercase())?;
+            }
+            Ok(())
+        })
+        .then("sort")
+        .then_fn(|reader, writer| io::copy(reader, writer).map(|_| ()))
+        .output()
+        .unwrap();
+    assert_eq!(b"A\nB\n", &out.stdout[..]);
+    assert_eq!(4, out.stages.len());
+    assert!(out.stages[1].command.contains("test_pipe_fn"));
+
+    match Pipe::new("true")
+        .then_fn(|_, _| Err(io::Error::other("no thanks")))
+        .then("cat")
+        .policy(SuccessPolicy::Pipefail)
+        .output()
+    {
+        Err(PipeError::StageFailed {
+            stage,
+            status,
+            stderr,
+            ..
+        }) => {
+            assert_eq!(1, stage);
+            assert_eq!(Some(1), status.code());
+            assert_eq!(b"no thanks\n", &stderr[..]);
+        }
+        other => panic!("expected a failed stage, got {:?}", other.map(|_| ())),
+    }
+
+    // Writing after the next command exits looks like `SIGPIPE`
+    let out = Pipe::new("true")
+        .then_fn(|_, writer| loop {
+            writer.write_all(b"y\n")?;
+        })
+        .then("head -n 1")
+        .output()
+        .unwrap();
+    assert_eq!(b"y\n", &out.stdout[..]);
+    assert_eq!(Some(13), out.stages[1].status.signal());
+
+    // A closure blocked on a full pipe is stopped by a timeout
+    match Pipe::new("true")
+        .then_fn(|_, writer| loop {
+            writer.write_all(b"y\n")?;
+        })
+        .then("sleep 10")
+        .timeout(Duration::from_millis(100))
+        .output()
+    {
+        Err(PipeError::TimedOut { output, .. }) => {
+            assert_eq!(Some(15), output.stages[1].status.signal());
+            assert_eq!(Some(15), output.stages[2].status.signal());
+        }
+        other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+    }
+
+    match Pipe::new("echo").then_fn(|_, _| Ok(())).finally() {
+        Err(PipeError::NotAProcess { stage: 1 }) => {}
+        other => panic!("expected no process, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipe_sink() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("pipers-sink-{}", std::process::id()));
+    let file = File::create(&path).unwrap();
+    let sink = Pipe::new("yes")
+        .then("head -c 100000")
+        .finally_to_writer(file)
+        .unwrap();
+    assert_eq!(100_000, sink.bytes);
+    assert_eq!(2, sink.output.stages.len());
+    assert!(sink.output.stdout.is_empty());
+    assert_eq!(100_000, fs::metadata(&path).unwrap().len());
+    fs::remove_file(&path).unwrap();
+
+    let mut chunks = Vec::new();
+    let sink = Pipe::new("printf abc")
+        .finally_to_fn(|chunk| {
+            chunks.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!((3, &b"abc"[..]), (sink.bytes, &chunks[..]));
+
+    // A failing callback stops the copying without leaving `yes` blocked
+    match Pipe::new("yes").finally_to_fn(|_| Err(io::Error::other("full"))) {
+        Err(PipeError::Io(e)) => assert_eq!("full", e.to_string()),
+        other => panic!("expected the callback's error, got {:?}", other.map(|_| ())),
+    }
+
+    match Pipe::new("sh -c 'echo partial; exit 2'").finally_to_writer(Vec::new()) {
+        Err(PipeError::StageFailed { status, .. }) => assert_eq!(Some(2), status.code()),
+        other => panic!("expected a failed stage, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_pipe_tee() {
+    use std::env;
+    use std::fs;
+
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let path = env::temp_dir().join(format!("pipers-tee-{}", std::process::id()));
+    let out = Pipe::new("yes")
+        .then("head -c 1000000")
+        .tee(File::create(&path).unwrap())
+        .then("wc -c")
+        .output()
+        .unwrap();
+    assert_eq!(b"1000000", out.stdout.trim_ascii());
+    assert_eq!("tee", out.stages[2].command);
+    assert_eq!(1_000_000, fs::metadata(&path).unwrap().len());
+
+    let out = Pipe::new("echo more")
+        .tee_file(&path, FileMode::Append)
+        .then("cat")
+        .output()
+        .unwrap();
+    assert_eq!(b"more\n", &out.stdout[..]);
+    assert_eq!(1_000_005, fs::metadata(&path).unwrap().len());
+    fs::remove_file(&path).unwrap();
+
+    // A broken sink doesn't stop the data from flowing
+    let out = Pipe::new("echo hello")
+        .tee(Broken)
+        .then("cat")
+        .output()
+        .unwrap();
+    assert_eq!(b"hello\n", &out.stdout[..]);
+    assert_eq!(Some(1), out.stages[1].status.code());
+    assert_eq!(b"disk full\n", &out.stages[1].stderr[..]);
+
+    let missing = env::temp_dir().join("pipers-no-such-dir").join("tee");
+    match Pipe::new("echo hello")
+        .tee_file(&missing, FileMode::Truncate)
+        .then("cat")
+        .policy(SuccessPolicy::Pipefail)
+        .output()
+    {
+        Err(PipeError::StageFailed { stage, stderr, .. }) => {
+            assert_eq!(1, stage);
+            assert!(String::from_utf8(stderr)
+                .unwrap()
+                .starts_with("failed to open"));
+        }
+        other => panic!("expected a failed stage, got {:?}", other.map(|_| ())),
+    }
+
+    // Closures writing into each other are all stopped when dropped
+    let mut running = Pipe::new("yes")
+        .tee(io::sink())
+        .then_fn(|input, output| {
+            io::copy(input, output)?;
+            Ok(())
+        })
+        .spawn()
+        .unwrap();
+    let mut first = [0; 2];
+    running.read_exact(&mut first).unwrap();
+    assert_eq!(b"y\n", &first);
+    // Give every pipe between the stages time to fill up
+    std::thread::sleep(Duration::from_millis(200));
+    drop(running);
+}
+
+#[test]
+fn test_pipe_fan_out() {
+    let out = Pipe::new("yes")
+        .then("head -c 1000000")
+        .fan_out(vec![
+            Pipe::new("wc -c"),
+            Pipe::new("tail -c 4").then("od -An -c"),
+            Pipe::new("head -c 2"),
+        ])
+        .unwrap();
+    assert_eq!(2, out.trunk.stages.len());
+    assert!(out.trunk.stdout.is_empty());
+    assert_eq!(b"1000000", out.branches[0].stdout.trim_ascii());
+    assert_eq!(2, out.branches[1].stages.len());
+    assert_eq!(b"y\n", &out.branches[2].stdout[..]);
+
+    // With every branch gone the trunk is stopped by `SIGPIPE`
+    let started = Instant::now();
+    let out = Pipe::new("yes")
+        .policy(SuccessPolicy::custom(|_, _| true))
+        .fan_out(vec![Pipe::new("head -n 1"), Pipe::new("head -n 2")])
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(b"y\ny\n", &out.branches[1].stdout[..]);
+
+    match Pipe::new("echo hello").fan_out(vec![Pipe::new("cat"), Pipe::new("sh -c 'exit 4'")]) {
+        Err(PipeError::BranchFailed { branch, source }) => {
+            assert_eq!(1, branch);
+            match *source {
+                PipeError::StageFailed { status, .. } => assert_eq!(Some(4), status.code()),
+                other => panic!("expected a failed stage, got {:?}", other),
+            }
+        }
+        other => panic!("expected a failed branch, got {:?}", other.map(|_| ())),
+    }
+
+    match Pipe::new("sh -c 'exit 5'").fan_out(vec![Pipe::new("cat")]) {
+        Err(PipeError::StageFailed { stage: 0, .. }) => {}
+        other => panic!("expected the trunk to fail, got {:?}", other.map(|_| ())),
+    }
+
+    match Pipe::new("echo").fan_out(vec![Pipe::new("cat"), Pipe::new("")]) {
+        Err(PipeError::BranchFailed { branch: 1, .. }) => {}
+        other => panic!("expected a failed branch, got {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::{PipeError, Result};
+use crate::running::RunningPipeline;
+use std::io::{self, BufRead, BufReader};
+
+/// An iterator over the stdout of the final command of a `Pipe`,
+/// split on a delimiter, returned by `Pipe::split`.
+///
+/// Each item is read as soon as the command writes it. Once the
+/// stdout is closed every command is waited on, and if the pipe
+/// failed the error is returned as the last item. Dropping the
+/// iterator before that kills every command.
+pub struct Split {
+    /// The running pipe, until it's been waited on.
+    reader: Option<BufReader<RunningPipeline>>,
+    /// An error from spawning the pipe, until it's been returned.
+    error: Option<PipeError>,
+    delimiter: u8,
+}
+
+impl Split {
+    pub(crate) fn new(pipeline: Result<RunningPipeline>, delimiter: u8) -> Split {
+        let (reader, error) = match pipeline {
+            Ok(pipeline) => (Some(BufReader::new(pipeline)), None),
+            Err(e) => (None, Some(e)),
+        };
+        Split {
+            reader,
+            error,
+            delimiter,
+        }
+    }
+}
+
+impl Iterator for Split {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e.into()));
+        }
+
+        let reader = self.reader.as_mut()?;
+        let mut item = Vec::new();
+        match reader.read_until(self.delimiter, &mut item) {
+            Ok(0) => {
+                let pipeline = self.reader.take()?.into_inner();
+                pipeline.wait().err().map(|e| Err(e.into()))
+            }
+            Ok(_) => {
+                if item.last() == Some(&self.delimiter) {
+                    item.pop();
+                }
+                Some(Ok(item))
+            }
+            Err(e) => {
+                self.reader = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// An iterator over the lines written to the stdout of the final
+/// command of a `Pipe`, returned by `Pipe::lines`.
+///
+/// This works just like `Split`, with each line losing its `\n` or
+/// `\r\n` ending. A line that isn't valid UTF-8 is returned as an
+/// error with the kind `InvalidData`.
+pub struct Lines {
+    split: Split,
+}
+
+impl Lines {
+    pub(crate) fn new(split: Split) -> Lines {
+        Lines { split }
+    }
+}
+
+impl Iterator for Lines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let line = match self.split.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut line = match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+        };
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        Some(Ok(line))
+    }
+}
@@ -0,0 +1,55 @@
+use std::process::ExitStatus;
+
+/// Everything collected from running a `Pipe` until
+/// every one of its stages exited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineOutput {
+    /// How each stage finished, in the order they were chained.
+    pub stages: Vec<StageOutput>,
+    /// What the final stage wrote to its stdout. This is empty
+    /// when the stdout wasn't captured, like with `Pipe::wait_all`.
+    pub stdout: Vec<u8>,
+}
+
+impl PipelineOutput {
+    /// Return the exit status of every stage, in the order
+    /// they were chained.
+    pub fn statuses(&self) -> Vec<ExitStatus> {
+        self.stages.iter().map(|stage| stage.status).collect()
+    }
+}
+
+/// Everything collected from running a `Pipe` into a writer with
+/// `Pipe::finally_to_writer` or `Pipe::finally_to_fn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkOutput {
+    /// How each stage finished. The `stdout` in here is always empty,
+    /// since it went to the writer instead.
+    pub output: PipelineOutput,
+    /// How many bytes the final stage wrote to its stdout.
+    pub bytes: u64,
+}
+
+/// Everything collected from running a `Pipe` into several others
+/// with `Pipe::fan_out`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FanOutOutput {
+    /// How each stage of the pipe that was fanned out finished. The
+    /// `stdout` in here is always empty, since it went to the branches.
+    pub trunk: PipelineOutput,
+    /// Everything collected from each branch, in the order they were
+    /// given.
+    pub branches: Vec<PipelineOutput>,
+}
+
+/// How a single stage of a pipeline finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageOutput {
+    /// The program the stage ran followed by its arguments.
+    pub command: String,
+    /// The exit status of the stage.
+    pub status: ExitStatus,
+    /// What the stage wrote to its stderr. This is empty unless
+    /// the stderr was captured with `Pipe::capture_stderr`.
+    pub stderr: Vec<u8>,
+}
@@ -0,0 +1,742 @@
+use crate::redirect::FileMode;
+use std::error;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// Errors that can happen while turning a command string
+/// into the program and arguments to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A quote was opened at `position` but never closed.
+    UnterminatedQuote { quote: char, position: usize },
+    /// The command ended with a backslash at `position`
+    /// that has nothing left to escape.
+    DanglingEscape { position: usize },
+    /// A pipeline had a stage with no command in it, either
+    /// at the `|` found at `position` or at the end of the input.
+    EmptyStage { position: usize },
+    /// The redirection at `position` wasn't followed by
+    /// the file it redirects to or from.
+    MissingRedirectTarget { position: usize },
+    /// The redirection `operator` at `position` isn't one
+    /// that can be used in a command.
+    UnsupportedRedirect { operator: String, position: usize },
+    /// The variable starting at `position` isn't written in one of
+    /// the supported forms, like `${NAME:-default}`, so it can't be
+    /// expanded.
+    BadSubstitution { position: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnterminatedQuote { quote, position } => write!(
+                f,
+                "unterminated {} quote starting at position {}",
+                quote, position
+            ),
+            ParseError::DanglingEscape { position } => {
+                write!(f, "dangling escape at position {}", position)
+            }
+            ParseError::EmptyStage { position } => {
+                write!(f, "empty pipeline stage at position {}", position)
+            }
+            ParseError::MissingRedirectTarget { position } => {
+                write!(f, "missing file for redirection at position {}", position)
+            }
+            ParseError::UnsupportedRedirect {
+                ref operator,
+                position,
+            } => write!(
+                f,
+                "unsupported redirection `{}` at position {}",
+                operator, position
+            ),
+            ParseError::BadSubstitution { position } => {
+                write!(f, "bad substitution at position {}", position)
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A single word of a command, made up of parts that
+/// were quoted differently.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Word(pub Vec<Part>);
+
+/// A piece of a `Word`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// Text that wasn't quoted or escaped.
+    Unquoted(String),
+    /// Text that was quoted or escaped, which is always
+    /// used exactly as it was written.
+    Quoted(String),
+    /// A variable written as `$NAME`, `${NAME}` or `${NAME:-default}`
+    /// outside of single quotes, along with the text it was written as.
+    Var {
+        name: String,
+        default: Option<String>,
+        raw: String,
+    },
+    /// A `~` at the start of a word, standing for the home directory.
+    Tilde,
+    /// The `$` at `position` starting a `${...}` that isn't one of the
+    /// supported forms, like `${A%b}`. It stands for a plain `$`, with
+    /// the rest of it read as ordinary text, unless variables are
+    /// expanded, in which case it's an error.
+    BadSubstitution { position: usize },
+}
+
+impl Word {
+    /// Return the word as it was written, minus its quoting and
+    /// without expanding anything.
+    pub fn literal(&self) -> String {
+        let mut literal = String::new();
+        for part in &self.0 {
+            match *part {
+                Part::Unquoted(ref text) | Part::Quoted(ref text) => literal.push_str(text),
+                Part::Var { ref raw, .. } => literal.push_str(raw),
+                Part::Tilde => literal.push('~'),
+                Part::BadSubstitution { .. } => literal.push('$'),
+            }
+        }
+        literal
+    }
+
+    /// Add a character to the end of the word.
+    fn push(&mut self, quoted: bool, c: char) {
+        match (self.0.last_mut(), quoted) {
+            (Some(&mut Part::Quoted(ref mut text)), true)
+            | (Some(&mut Part::Unquoted(ref mut text)), false) => text.push(c),
+            (_, true) => self.0.push(Part::Quoted(c.to_string())),
+            (_, false) => self.0.push(Part::Unquoted(c.to_string())),
+        }
+    }
+
+    /// Mark the word as quoted, so that `''` still counts as a word.
+    fn quote(&mut self) {
+        if let Some(&Part::Quoted(_)) = self.0.last() {
+            return;
+        }
+        self.0.push(Part::Quoted(String::new()));
+    }
+
+    /// Return the word if it's an unquoted number like the `2` in `2>`.
+    fn io_number(&self) -> Option<String> {
+        match self.0[..] {
+            [Part::Unquoted(ref text)] if text.chars().all(|c| c.is_ascii_digit()) => {
+                Some(text.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A redirection written in a command, like `2>&1` or `> out.txt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// `< file`
+    Stdin(Word),
+    /// `> file` or `>> file`
+    Stdout(Word, FileMode),
+    /// `2> file` or `2>> file`
+    Stderr(Word, FileMode),
+    /// `2>&1`
+    StderrToStdout,
+    /// `&> file` or `&>> file`
+    Both(Word, FileMode),
+}
+
+/// A single command split into its words and redirections.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Parsed {
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// The pieces a command string is broken into.
+enum Token {
+    /// A single argument.
+    Word(Word),
+    /// An unquoted `|` separating two stages of a pipeline.
+    Pipe,
+    /// An unquoted redirection operator, which is followed by
+    /// the word it redirects to unless it's `2>&1`.
+    Redirect(Operator),
+}
+
+/// The redirection operators that can be used in a command.
+enum Operator {
+    /// `<`
+    In,
+    /// `>` or `>>`
+    Out(FileMode),
+    /// `2>` or `2>>`
+    Err(FileMode),
+    /// `2>&1`
+    ErrToOut,
+    /// `&>` or `&>>`
+    Both(FileMode),
+}
+
+/// Breaks a command string up into `Token`s following the
+/// quoting rules of a POSIX shell.
+struct Lexer<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    /// Whether an unquoted `|` ends a word and becomes a
+    /// `Token::Pipe` rather than being part of the word.
+    pipes: bool,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str, pipes: bool) -> Lexer<'a> {
+        Lexer {
+            chars: input.char_indices().peekable(),
+            pipes,
+        }
+    }
+
+    /// Return the next token along with the position it started at,
+    /// or `None` once the input has been used up.
+    fn next_token(&mut self) -> Result<Option<(usize, Token)>, ParseError> {
+        while let Some(&(_, ' ')) | Some(&(_, '\t')) | Some(&(_, '\n')) = self.chars.peek() {
+            self.chars.next();
+        }
+
+        let start = match self.chars.peek() {
+            Some(&(position, '|')) if self.pipes => {
+                self.chars.next();
+                return Ok(Some((position, Token::Pipe)));
+            }
+            Some(&(position, _)) => position,
+            None => return Ok(None),
+        };
+
+        let mut word = Word::default();
+        // Tracks whether anything besides a line continuation was read
+        let mut in_word = false;
+
+        while let Some(&(position, c)) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\n' => break,
+                '|' if self.pipes => break,
+                '<' | '>' | '&' if self.at_operator() => {
+                    if !in_word {
+                        return self.operator(None, start).map(Some);
+                    }
+                    if let Some(fd) = word.io_number() {
+                        return self.operator(Some(fd), start).map(Some);
+                    }
+                    break;
+                }
+                _ => {}
+            }
+            self.chars.next();
+
+            match c {
+                '\'' => {
+                    in_word = true;
+                    word.quote();
+                    loop {
+                        match self.chars.next() {
+                            Some((_, '\'')) => break,
+                            Some((_, c)) => word.push(true, c),
+                            None => {
+                                return Err(ParseError::UnterminatedQuote {
+                                    quote: '\'',
+                                    position,
+                                })
+                            }
+                        }
+                    }
+                }
+                '"' => {
+                    in_word = true;
+                    word.quote();
+                    loop {
+                        match self.chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match self.chars.next() {
+                                Some((_, '\n')) => {}
+                                Some((_, c @ '$')) | Some((_, c @ '`')) | Some((_, c @ '"'))
+                                | Some((_, c @ '\\')) => word.push(true, c),
+                                Some((_, c)) => {
+                                    word.push(true, '\\');
+                                    word.push(true, c);
+                                }
+                                None => {
+                                    return Err(ParseError::UnterminatedQuote {
+                                        quote: '"',
+                                        position,
+                                    })
+                                }
+                            },
+                            Some((dollar, '$')) => match self.variable(dollar) {
+                                Some(var) => word.0.push(var),
+                                None => word.push(true, '$'),
+                            },
+                            Some((_, c)) => word.push(true, c),
+                            None => {
+                                return Err(ParseError::UnterminatedQuote {
+                                    quote: '"',
+                                    position,
+                                })
+                            }
+                        }
+                    }
+                }
+                '\\' => match self.chars.next() {
+                    // A backslash before a newline joins the two lines
+                    Some((_, '\n')) => {}
+                    Some((_, c)) => {
+                        in_word = true;
+                        word.push(true, c);
+                    }
+                    None => return Err(ParseError::DanglingEscape { position }),
+                },
+                '$' => {
+                    in_word = true;
+                    match self.variable(position) {
+                        Some(var) => word.0.push(var),
+                        None => word.push(false, '$'),
+                    }
+                }
+                '~' if !in_word && (self.at_delimiter() || self.at('/')) => {
+                    in_word = true;
+                    word.0.push(Part::Tilde);
+                }
+                c => {
+                    in_word = true;
+                    word.push(false, c);
+                }
+            }
+        }
+
+        if in_word {
+            Ok(Some((start, Token::Word(word))))
+        } else {
+            // Nothing but line continuations, look for the next token
+            self.next_token()
+        }
+    }
+
+    /// Whether a redirection operator starts at the next character.
+    fn at_operator(&self) -> bool {
+        let mut ahead = self.chars.clone();
+        match ahead.next() {
+            Some((_, '<')) | Some((_, '>')) => true,
+            Some((_, '&')) => ahead.next().map(|(_, c)| c) == Some('>'),
+            _ => false,
+        }
+    }
+
+    /// Read the variable following the `$` at `position`, or return
+    /// `None` if the `$` doesn't start one and is just a `$`.
+    ///
+    /// A `${` that doesn't start a supported form is returned as a
+    /// `Part::BadSubstitution`, with nothing after the `$` read yet.
+    fn variable(&mut self, position: usize) -> Option<Part> {
+        let start = self.chars.clone();
+        let mut raw = String::from("$");
+        let braced = self.eat('{');
+        if braced {
+            raw.push('{');
+        }
+
+        let mut name = String::new();
+        while let Some(&(_, c)) = self.chars.peek() {
+            let valid =
+                c == '_' || c.is_ascii_alphabetic() || (c.is_ascii_digit() && !name.is_empty());
+            if !valid {
+                break;
+            }
+            self.chars.next();
+            name.push(c);
+        }
+        raw.push_str(&name);
+
+        if !braced {
+            return if name.is_empty() {
+                None
+            } else {
+                Some(Part::Var {
+                    name,
+                    default: None,
+                    raw,
+                })
+            };
+        }
+
+        let mut default = None;
+        if !name.is_empty() && self.eat(':') {
+            if !self.eat('-') {
+                self.chars = start;
+                return Some(Part::BadSubstitution { position });
+            }
+            let mut text = String::new();
+            while let Some(&(_, c)) = self.chars.peek() {
+                if c == '}' {
+                    break;
+                }
+                self.chars.next();
+                text.push(c);
+            }
+            raw.push_str(":-");
+            raw.push_str(&text);
+            default = Some(text);
+        }
+
+        if name.is_empty() || !self.eat('}') {
+            self.chars = start;
+            return Some(Part::BadSubstitution { position });
+        }
+        raw.push('}');
+
+        Some(Part::Var { name, default, raw })
+    }
+
+    /// Whether the next character is `c`.
+    fn at(&mut self, c: char) -> bool {
+        self.chars.peek().map(|&(_, next)| next) == Some(c)
+    }
+
+    /// Consume the next character if it's `c`.
+    fn eat(&mut self, c: char) -> bool {
+        match self.chars.peek() {
+            Some(&(_, next)) if next == c => {
+                self.chars.next();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Read the redirection operator at the next character, where
+    /// `fd` is the number written right in front of it, if any.
+    fn operator(&mut self, fd: Option<String>, start: usize) -> Result<(usize, Token), ParseError> {
+        let mut text = fd.clone().unwrap_or_default();
+        let fd = fd.as_deref();
+        let first = match self.chars.next() {
+            Some((_, c)) => c,
+            None => return Err(ParseError::MissingRedirectTarget { position: start }),
+        };
+        text.push(first);
+
+        let operator = match first {
+            '<' => match self.chars.peek() {
+                Some(&(_, c)) if c == '<' || c == '>' || c == '&' => {
+                    self.chars.next();
+                    text.push(c);
+                    None
+                }
+                _ => match fd {
+                    None | Some("0") => Some(Operator::In),
+                    _ => None,
+                },
+            },
+            '>' => {
+                let mode = if self.eat('>') {
+                    text.push('>');
+                    FileMode::Append
+                } else {
+                    FileMode::Truncate
+                };
+
+                if self.eat('&') {
+                    text.push('&');
+                    let target = match self.chars.peek() {
+                        Some(&(_, c)) if c.is_ascii_digit() || c == '-' => {
+                            self.chars.next();
+                            text.push(c);
+                            Some(c)
+                        }
+                        _ => None,
+                    };
+                    match (fd, mode, target, self.at_delimiter()) {
+                        (Some("2"), FileMode::Truncate, Some('1'), true) => {
+                            Some(Operator::ErrToOut)
+                        }
+                        _ => None,
+                    }
+                } else if self.eat('|') {
+                    text.push('|');
+                    None
+                } else {
+                    match fd {
+                        None | Some("1") => Some(Operator::Out(mode)),
+                        Some("2") => Some(Operator::Err(mode)),
+                        _ => None,
+                    }
+                }
+            }
+            _ => {
+                // `&` is only read as an operator when `>` follows it
+                self.eat('>');
+                text.push('>');
+                let mode = if self.eat('>') {
+                    text.push('>');
+                    FileMode::Append
+                } else {
+                    FileMode::Truncate
+                };
+                match fd {
+                    None => Some(Operator::Both(mode)),
+                    _ => None,
+                }
+            }
+        };
+
+        match operator {
+            Some(operator) => Ok((start, Token::Redirect(operator))),
+            None => Err(ParseError::UnsupportedRedirect {
+                operator: text,
+                position: start,
+            }),
+        }
+    }
+
+    /// Whether the next character ends a word, or there is none.
+    fn at_delimiter(&mut self) -> bool {
+        match self.chars.peek() {
+            None | Some(&(_, ' ')) | Some(&(_, '\t')) | Some(&(_, '\n')) => true,
+            Some(&(_, '|')) => self.pipes,
+            Some(&(_, '<')) | Some(&(_, '>')) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Split a command string into words following the quoting rules
+/// of a POSIX shell, pulling out any redirections along the way.
+///
+/// Words are separated by unquoted spaces, tabs and newlines.
+/// Single quotes preserve everything up to the closing quote,
+/// double quotes preserve everything but allow `\` to escape
+/// `$`, `` ` ``, `"`, `\` and a newline, and an unquoted `\`
+/// escapes whatever character follows it. Quotes with nothing
+/// between them produce an empty argument.
+///
+/// The redirections `<`, `>`, `>>`, `2>`, `2>>`, `2>&1`, `&>` and
+/// `&>>` are recognised when they aren't quoted, and any other
+/// use of `<` or `>` is an error.
+///
+/// Variables like `$NAME`, `${NAME}` and `${NAME:-default}` outside
+/// of single quotes, and a `~` starting a word, are kept apart from
+/// the rest of their word so they can be expanded later on.
+pub fn split(command: &str) -> Result<Parsed, ParseError> {
+    let mut lexer = Lexer::new(command, false);
+    parse_command(&mut lexer).map(|(parsed, _)| parsed)
+}
+
+/// Split a whole pipeline like `ls / | grep usr` into the words
+/// and redirections of each of its stages.
+///
+/// Stages are separated by unquoted `|` characters and are split
+/// the same way as `split`. A stage without any words, such as
+/// in `a || b` or `a |`, is an error.
+pub fn split_pipeline(pipeline: &str) -> Result<Vec<Parsed>, ParseError> {
+    let mut lexer = Lexer::new(pipeline, true);
+    let mut stages = Vec::new();
+    loop {
+        let (stage, pipe) = parse_command(&mut lexer)?;
+        if stage.words.is_empty() {
+            return Err(ParseError::EmptyStage {
+                position: pipe.unwrap_or(pipeline.len()),
+            });
+        }
+        stages.push(stage);
+
+        if pipe.is_none() {
+            return Ok(stages);
+        }
+    }
+}
+
+/// Read the words and redirections of a single command, up to the
+/// end of the input or a `|`, whose position is returned as well.
+fn parse_command(lexer: &mut Lexer) -> Result<(Parsed, Option<usize>), ParseError> {
+    let mut parsed = Parsed::default();
+    while let Some((position, token)) = lexer.next_token()? {
+        let operator = match token {
+            Token::Word(word) => {
+                parsed.words.push(word);
+                continue;
+            }
+            Token::Pipe => return Ok((parsed, Some(position))),
+            Token::Redirect(Operator::ErrToOut) => {
+                parsed.redirects.push(Redirect::StderrToStdout);
+                continue;
+            }
+            Token::Redirect(operator) => operator,
+        };
+
+        let target = match lexer.next_token()? {
+            Some((_, Token::Word(word))) => word,
+            _ => return Err(ParseError::MissingRedirectTarget { position }),
+        };
+        parsed.redirects.push(match operator {
+            Operator::In => Redirect::Stdin(target),
+            Operator::Out(mode) => Redirect::Stdout(target, mode),
+            Operator::Err(mode) => Redirect::Stderr(target, mode),
+            Operator::Both(mode) => Redirect::Both(target, mode),
+            Operator::ErrToOut => Redirect::StderrToStdout,
+        });
+    }
+
+    Ok((parsed, None))
+}
+
+#[test]
+fn test_split_quotes() {
+    assert_eq!(
+        literals(split(r#"awk '{print $1}' "hello world" a\ b "" 'it'\''s' "\$x \q""#).unwrap()),
+        vec![
+            "awk",
+            "{print $1}",
+            "hello world",
+            "a b",
+            "",
+            "it's",
+            "$x \\q"
+        ]
+    );
+}
+
+#[test]
+fn test_split_errors() {
+    assert_eq!(
+        split("grep 'oops"),
+        Err(ParseError::UnterminatedQuote {
+            quote: '\'',
+            position: 5
+        })
+    );
+    assert_eq!(
+        split("echo \"a\\\""),
+        Err(ParseError::UnterminatedQuote {
+            quote: '"',
+            position: 5
+        })
+    );
+    assert_eq!(
+        split("echo \\"),
+        Err(ParseError::DanglingEscape { position: 5 })
+    );
+}
+
+#[test]
+fn test_split_pipeline() {
+    let stages = split_pipeline("ls / | grep 'a|b'|head -c 1").unwrap();
+    assert_eq!(
+        stages.into_iter().map(literals).collect::<Vec<_>>(),
+        vec![
+            vec!["ls", "/"],
+            vec!["grep", "a|b"],
+            vec!["head", "-c", "1"]
+        ]
+    );
+    assert_eq!(
+        split_pipeline("a || b"),
+        Err(ParseError::EmptyStage { position: 3 })
+    );
+    assert_eq!(
+        split_pipeline("a | b |"),
+        Err(ParseError::EmptyStage { position: 7 })
+    );
+}
+
+#[test]
+fn test_split_redirects() {
+    let parsed = split("sort<in >out 2>>err '>' 2\\>x a2>'&1' &>>all").unwrap();
+    let unquoted = |text: &str| Word(vec![Part::Unquoted(text.to_string())]);
+    assert_eq!(
+        parsed.redirects,
+        vec![
+            Redirect::Stdin(unquoted("in")),
+            Redirect::Stdout(unquoted("out"), FileMode::Truncate),
+            Redirect::Stderr(unquoted("err"), FileMode::Append),
+            Redirect::Stdout(
+                Word(vec![Part::Quoted("&1".to_string())]),
+                FileMode::Truncate
+            ),
+            Redirect::Both(unquoted("all"), FileMode::Append),
+        ]
+    );
+    assert_eq!(literals(parsed), vec!["sort", ">", "2>x", "a2"]);
+    assert_eq!(
+        split("make 2>&1").unwrap().redirects,
+        vec![Redirect::StderrToStdout]
+    );
+
+    assert_eq!(
+        split("echo >"),
+        Err(ParseError::MissingRedirectTarget { position: 5 })
+    );
+    assert_eq!(
+        split_pipeline("echo 2> | cat"),
+        Err(ParseError::MissingRedirectTarget { position: 5 })
+    );
+    for &(command, operator, position) in &[
+        ("cat << EOF", "<<", 4),
+        ("echo >&2", ">&2", 5),
+        ("echo 3>x", "3>", 5),
+        ("echo 1>&2", "1>&2", 5),
+        ("echo >| x", ">|", 5),
+    ] {
+        assert_eq!(
+            split(command),
+            Err(ParseError::UnsupportedRedirect {
+                operator: operator.to_string(),
+                position,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_split_variables() {
+    let parsed = split(r#"ls ~ ~/a a~ $HOME/x "${A:-b c}" '$B' \$C $ $1 ~user"#).unwrap();
+    let var = |name: &str, default: Option<&str>, raw: &str| Part::Var {
+        name: name.to_string(),
+        default: default.map(|d| d.to_string()),
+        raw: raw.to_string(),
+    };
+    assert_eq!(
+        parsed.words[1..6].to_vec(),
+        vec![
+            Word(vec![Part::Tilde]),
+            Word(vec![Part::Tilde, Part::Unquoted("/a".to_string())]),
+            Word(vec![Part::Unquoted("a~".to_string())]),
+            Word(vec![
+                var("HOME", None, "$HOME"),
+                Part::Unquoted("/x".to_string())
+            ]),
+            Word(vec![
+                Part::Quoted(String::new()),
+                var("A", Some("b c"), "${A:-b c}")
+            ]),
+        ]
+    );
+    assert_eq!(
+        literals(parsed)[6..].to_vec(),
+        vec!["$B", "$C", "$", "$1", "~user"]
+    );
+
+    // Forms that aren't supported are read as if `$` wasn't special
+    for &(command, position) in &[("echo ${A", 5), ("echo ${}", 5), ("echo \"${A:=b}\"", 6)] {
+        let parsed = split(command).unwrap();
+        assert!(parsed.words[1]
+            .0
+            .contains(&Part::BadSubstitution { position }));
+        assert_eq!(&command[5..].replace('"', ""), &parsed.words[1].literal());
+    }
+}
+
+#[cfg(test)]
+fn literals(parsed: Parsed) -> Vec<String> {
+    parsed.words.iter().map(Word::literal).collect()
+}
@@ -0,0 +1,90 @@
+use crate::error::{PipeError, Result};
+use crate::output::{PipelineOutput, StageOutput};
+use std::fmt;
+
+/// A function deciding whether a single stage succeeded,
+/// given its index and how it finished.
+pub type StagePredicate = dyn Fn(usize, &StageOutput) -> bool + Send + Sync;
+
+/// Decides whether a finished pipeline counts as a success
+/// based on how each of its stages exited.
+#[derive(Default)]
+pub enum SuccessPolicy {
+    /// Only the final stage has to exit successfully, which is
+    /// what a shell does by default.
+    #[default]
+    LastStage,
+    /// Every stage has to exit successfully, like a shell
+    /// with `set -o pipefail`.
+    Pipefail,
+    /// Every stage is checked with a function that gets the index
+    /// of the stage and how it finished, returning whether that
+    /// counts as a success.
+    Custom(Box<StagePredicate>),
+}
+
+impl SuccessPolicy {
+    /// Creates a `SuccessPolicy::Custom` from a function, for
+    /// example to allow `grep` to find nothing or an earlier
+    /// stage to be killed by the `SIGPIPE` that `head` causes:
+    ///
+    /// ```rust
+    /// use pipers::SuccessPolicy;
+    /// use std::os::unix::process::ExitStatusExt;
+    ///
+    /// let policy = SuccessPolicy::custom(|_, stage| {
+    ///     stage.status.success()
+    ///         || (stage.command.starts_with("grep ") && stage.status.code() == Some(1))
+    ///         || stage.status.signal() == Some(13)
+    /// });
+    /// ```
+    pub fn custom<F>(f: F) -> SuccessPolicy
+    where
+        F: Fn(usize, &StageOutput) -> bool + Send + Sync + 'static,
+    {
+        SuccessPolicy::Custom(Box::new(f))
+    }
+
+    /// Return the index of the first stage that failed
+    /// according to this policy, if any did.
+    pub fn failed_stage(&self, stages: &[StageOutput]) -> Option<usize> {
+        match *self {
+            SuccessPolicy::LastStage => match stages.last() {
+                Some(stage) if !stage.status.success() => Some(stages.len() - 1),
+                _ => None,
+            },
+            SuccessPolicy::Pipefail => stages.iter().position(|stage| !stage.status.success()),
+            SuccessPolicy::Custom(ref f) => stages
+                .iter()
+                .enumerate()
+                .position(|(index, stage)| !f(index, stage)),
+        }
+    }
+
+    /// Return the output of a finished pipe, or an error naming
+    /// the first stage that failed according to this policy.
+    pub(crate) fn check(&self, output: PipelineOutput) -> Result<PipelineOutput> {
+        match self.failed_stage(&output.stages) {
+            Some(index) => {
+                let stage = &output.stages[index];
+                Err(PipeError::StageFailed {
+                    stage: index,
+                    command: stage.command.clone(),
+                    status: stage.status,
+                    stderr: stage.stderr.clone(),
+                })
+            }
+            None => Ok(output),
+        }
+    }
+}
+
+impl fmt::Debug for SuccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SuccessPolicy::LastStage => f.write_str("LastStage"),
+            SuccessPolicy::Pipefail => f.write_str("Pipefail"),
+            SuccessPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
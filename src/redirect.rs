@@ -0,0 +1,151 @@
+use crate::error::{PipeError, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, PipeReader, Read, Write};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdout, Command, Stdio};
+
+/// Where the stderr of a stage goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stderr {
+    /// Write it to the stderr of this process.
+    Inherit,
+    /// Throw it away, like `2>/dev/null` in a shell.
+    Null,
+    /// Collect it into the stage's `StageOutput`, like
+    /// `Pipe::capture_stderr` does for every stage.
+    Capture,
+    /// Write it to a file, like `2>file` or `2>>file` in a shell.
+    File(PathBuf, FileMode),
+    /// Send it wherever the stdout of the stage goes, like `2>&1`
+    /// in a shell, so that it flows into the next stage.
+    ///
+    /// When the final stage merges its stderr there is no
+    /// `ChildStdout` for `peek` or `finally` to hand out, so
+    /// read its output with `Pipe::output` instead.
+    Merge,
+}
+
+/// How a file that's written to is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Replace whatever was in the file, like `>` in a shell.
+    Truncate,
+    /// Add to the end of the file, like `>>` in a shell.
+    Append,
+}
+
+impl FileMode {
+    /// Open the file at `path` for writing, creating it if needed.
+    pub fn open(self, path: &Path) -> Result<File> {
+        let mut options = OpenOptions::new();
+        match self {
+            FileMode::Truncate => options.write(true).truncate(true),
+            FileMode::Append => options.append(true),
+        };
+
+        options
+            .create(true)
+            .open(path)
+            .map_err(|source| PipeError::OpenFailed {
+                path: path.to_path_buf(),
+                source,
+            })
+    }
+}
+
+/// Where the stdout of a stage goes.
+pub enum Target {
+    /// Into a pipe, to be read by the next stage or by us.
+    Piped,
+    /// Straight to the stdout of this process.
+    Inherit,
+    /// Straight into a file that's already open.
+    File(File),
+}
+
+/// The read end of a stage's stdout.
+pub enum StageStdout {
+    Child(ChildStdout),
+    /// A pipe made by us, for when the stage's stderr had to
+    /// be given the same pipe to write into.
+    Pipe(PipeReader),
+    /// The stdout went somewhere else, like a file, so there's
+    /// nothing to read.
+    Redirected,
+}
+
+impl StageStdout {
+    /// Copy everything left to read into `out`, returning how many
+    /// bytes were copied. Each kind of stdout is copied as itself so
+    /// that `io::copy` can have the kernel move the bytes when `out`
+    /// is a file, pipe or socket.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, out: &mut W) -> io::Result<u64> {
+        match *self {
+            StageStdout::Child(ref mut stdout) => io::copy(stdout, out),
+            StageStdout::Pipe(ref mut reader) => io::copy(reader, out),
+            StageStdout::Redirected => Ok(0),
+        }
+    }
+}
+
+impl Read for StageStdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            StageStdout::Child(ref mut stdout) => stdout.read(buf),
+            StageStdout::Pipe(ref mut reader) => reader.read(buf),
+            StageStdout::Redirected => Ok(0),
+        }
+    }
+}
+
+impl From<StageStdout> for Stdio {
+    fn from(stdout: StageStdout) -> Stdio {
+        match stdout {
+            StageStdout::Child(stdout) => Stdio::from(stdout),
+            StageStdout::Pipe(reader) => Stdio::from(reader),
+            StageStdout::Redirected => Stdio::null(),
+        }
+    }
+}
+
+/// Set up the stdout and stderr of `command`. When nothing says
+/// where the stderr goes, it's left however the command had it.
+///
+/// Returns the read end of the stdout if a pipe had to be made
+/// here rather than by `Command` itself.
+pub fn wire(
+    command: &mut Command,
+    stdout: Target,
+    stderr: Option<&Stderr>,
+) -> Result<Option<PipeReader>> {
+    match (stdout, stderr) {
+        (Target::Piped, Some(&Stderr::Merge)) => {
+            let (reader, writer) = io::pipe()?;
+            command.stdout(writer.try_clone()?).stderr(writer);
+            return Ok(Some(reader));
+        }
+        (Target::Inherit, Some(&Stderr::Merge)) => {
+            let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+            command.stdout(Stdio::inherit()).stderr(stdout);
+            return Ok(None);
+        }
+        (Target::File(file), Some(&Stderr::Merge)) => {
+            command.stdout(file.try_clone()?).stderr(file);
+            return Ok(None);
+        }
+        (Target::Piped, _) => command.stdout(Stdio::piped()),
+        (Target::Inherit, _) => command.stdout(Stdio::inherit()),
+        (Target::File(file), _) => command.stdout(file),
+    };
+
+    match stderr {
+        Some(&Stderr::Inherit) => command.stderr(Stdio::inherit()),
+        Some(&Stderr::Null) => command.stderr(Stdio::null()),
+        Some(&Stderr::Capture) => command.stderr(Stdio::piped()),
+        Some(&Stderr::File(ref path, mode)) => command.stderr(mode.open(path)?),
+        Some(&Stderr::Merge) => unreachable!("merged stderr is wired along with stdout"),
+        None => command,
+    };
+    Ok(None)
+}
@@ -0,0 +1,419 @@
+use crate::capture::Capture;
+use crate::error::{PipeError, Result};
+use crate::function::Worker;
+use crate::input::Feeder;
+use crate::output::{PipelineOutput, StageOutput};
+use crate::policy::SuccessPolicy;
+use crate::redirect::StageStdout;
+use crate::timeout::{self, Stages, Watchdog};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A command that has been spawned as part of a `Pipe`.
+pub struct Running {
+    /// The command line the child was spawned from, or the name of
+    /// the closure the worker runs.
+    pub command: String,
+    pub process: Process,
+    /// The read end of the child's stdout, until it's handed
+    /// to the next command or read.
+    pub stdout: Option<StageStdout>,
+    /// The thread collecting the child's stderr, if it's captured.
+    pub stderr: Option<Capture>,
+}
+
+/// What a running stage runs as.
+pub enum Process {
+    Child(Child),
+    /// A closure run on a thread of this process.
+    Worker(Worker),
+}
+
+impl Process {
+    /// Return whether the stage has finished, without waiting.
+    fn finished(&mut self) -> io::Result<bool> {
+        match *self {
+            Process::Child(ref mut child) => child.try_wait().map(|status| status.is_some()),
+            Process::Worker(ref worker) => Ok(worker.is_finished()),
+        }
+    }
+}
+
+/// What happens to the commands of a `RunningPipeline` that are
+/// still running when it's dropped without being waited on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnDrop {
+    /// Kill every command and reap it.
+    #[default]
+    Kill,
+    /// Wait for every command to exit on its own. The stdout of the
+    /// final command is closed first, so a command that's still
+    /// writing to it is stopped by `SIGPIPE` instead of blocking.
+    Wait,
+    /// Leave the commands running without waiting on them.
+    Detach,
+}
+
+/// Every command of a `Pipe` that has been spawned, returned by
+/// `Pipe::spawn`.
+///
+/// Reading from it reads the stdout of the final command. If it's
+/// dropped before `wait` is called, the commands are killed unless
+/// a different `OnDrop` is chosen, so that none are left behind
+/// when returning early or panicking.
+pub struct RunningPipeline {
+    /// Commands that were already spawned, in pipeline order.
+    pub(crate) running: Vec<Running>,
+    /// The thread writing the pipe's input into the first command.
+    pub(crate) feeder: Option<Feeder>,
+    /// Decides whether the pipe succeeded once every command exited.
+    pub(crate) policy: SuccessPolicy,
+    /// How long the pipe is allowed to run for, if there's a limit.
+    pub(crate) timeout: Option<Duration>,
+    /// How long commands get to exit after being sent `SIGTERM`
+    /// once the pipe has run out of time.
+    pub(crate) grace: Duration,
+    /// When the pipe runs out of time, from the moment it started.
+    pub(crate) deadline: Option<Instant>,
+    /// The process group every command runs in, if they were given
+    /// one of their own.
+    pub(crate) group: Option<Pid>,
+    /// Enforces the deadline while the final stdout is being read.
+    watchdog: Option<Watchdog>,
+    on_drop: OnDrop,
+}
+
+impl RunningPipeline {
+    pub(crate) fn new() -> RunningPipeline {
+        RunningPipeline {
+            running: Vec::new(),
+            feeder: None,
+            policy: SuccessPolicy::default(),
+            timeout: None,
+            grace: timeout::DEFAULT_GRACE,
+            deadline: None,
+            group: None,
+            watchdog: None,
+            on_drop: OnDrop::default(),
+        }
+    }
+
+    /// Choose what happens to the commands if this is dropped
+    /// before they're waited on.
+    pub fn on_drop(mut self, on_drop: OnDrop) -> RunningPipeline {
+        self.on_drop = on_drop;
+        self
+    }
+
+    /// Start enforcing the deadline, if there is one, for while the
+    /// stdout of the final command is read rather than waited on.
+    pub(crate) fn watch(&mut self) {
+        if let Some(deadline) = self.deadline {
+            self.watchdog = Some(Watchdog::start(self.stages(), deadline, self.grace));
+        }
+    }
+
+    /// Return every stage, for signalling all of them at once.
+    fn stages(&self) -> Stages {
+        Stages {
+            group: self.group,
+            ids: self.ids(),
+            workers: self
+                .running
+                .iter()
+                .filter_map(|r| match r.process {
+                    Process::Worker(ref worker) => Some(worker.stopper()),
+                    Process::Child(_) => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Keep the watchdog from signalling the commands from now on,
+    /// before any of them is reaped.
+    fn disarm(&self) {
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.disarm();
+        }
+    }
+
+    /// Return the process ID of every command, in pipeline order.
+    /// Stages running a closure don't have one, so they're left out.
+    pub fn ids(&self) -> Vec<u32> {
+        self.running
+            .iter()
+            .filter_map(|r| match r.process {
+                Process::Child(ref child) => Some(child.id()),
+                Process::Worker(_) => None,
+            })
+            .collect()
+    }
+
+    /// Return the ID of the process group the commands run in, unless
+    /// they were left in the process group of this process.
+    pub fn group_id(&self) -> Option<u32> {
+        self.group.map(|group| group.as_raw() as u32)
+    }
+
+    /// Send `signal` to every command that's still running.
+    ///
+    /// When the commands have a process group of their own, the signal
+    /// is sent to the whole group, so anything they started themselves
+    /// gets it as well. Nothing is sent once every command has exited,
+    /// since the group may not exist anymore.
+    ///
+    /// Stages running a closure can't be sent signals, but `SIGTERM` and
+    /// `SIGKILL` make every read and write they do from then on fail.
+    ///
+    /// ```rust
+    /// use pipers::{Pipe, PipeError, Signal};
+    ///
+    /// let mut running = Pipe::new("sleep 10").then("cat").spawn().unwrap();
+    /// running.signal(Signal::SIGINT).unwrap();
+    /// match running.wait() {
+    ///     Err(PipeError::StageFailed { stage, .. }) => assert_eq!(1, stage),
+    ///     _ => panic!("the pipe should have been interrupted"),
+    /// }
+    /// ```
+    pub fn signal(&mut self, signal: Signal) -> io::Result<()> {
+        if let Some(ref watchdog) = self.watchdog {
+            if let Some(_armed) = watchdog.armed() {
+                // Nothing has been reaped while the watchdog is armed, and
+                // checking which commands are still running would reap them
+                return self.stages().signal(signal);
+            }
+        }
+
+        if signal == Signal::SIGTERM || signal == Signal::SIGKILL {
+            for running in &self.running {
+                if let Process::Worker(ref worker) = running.process {
+                    worker.stop(signal);
+                }
+            }
+        }
+
+        let unfinished = self.unfinished()?;
+        if unfinished.is_empty() {
+            return Ok(());
+        }
+        match self.group {
+            Some(group) => signal::killpg(group, signal)?,
+            None => {
+                for id in unfinished {
+                    signal::kill(Pid::from_raw(id as i32), signal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Kill every command that's still running. The commands still
+    /// have to be waited on with `wait` to find out how they exited.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.signal(Signal::SIGKILL)
+    }
+
+    /// Return the process IDs of the commands that haven't exited yet.
+    ///
+    /// Commands that did exit aren't reaped until they're waited on,
+    /// so none of these IDs can have been reused by something else.
+    pub(crate) fn unfinished(&mut self) -> io::Result<Vec<u32>> {
+        self.disarm();
+        let mut unfinished = Vec::new();
+        for running in &mut self.running {
+            if let Process::Child(ref mut child) = running.process {
+                if child.try_wait()?.is_none() {
+                    unfinished.push(child.id());
+                }
+            }
+        }
+        Ok(unfinished)
+    }
+
+    /// Return whether every stage has finished, including the ones
+    /// running a closure.
+    pub(crate) fn finished(&mut self) -> io::Result<bool> {
+        self.disarm();
+        for running in &mut self.running {
+            if !running.process.finished()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Wait until every command has exited, collecting whatever is
+    /// left of the stdout of the final command along with the exit
+    /// status of every command.
+    ///
+    /// An error is returned naming the first command that failed
+    /// according to the pipe's `SuccessPolicy`.
+    pub fn wait(mut self) -> Result<PipelineOutput> {
+        let mut stdout = Vec::new();
+        match self.wait_into(&mut stdout) {
+            Ok((mut output, _)) => {
+                output.stdout = stdout;
+                Ok(output)
+            }
+            Err(PipeError::TimedOut {
+                timeout,
+                mut output,
+            }) => {
+                output.stdout = stdout;
+                Err(PipeError::TimedOut { timeout, output })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait on every running command and check how they finished.
+    ///
+    /// If the final command has a piped stdout, whatever it writes
+    /// is copied into `out`, and how many bytes that was is returned
+    /// along with the output. When the pipe has a deadline it's copied
+    /// from another thread, so that the commands can be stopped if
+    /// they don't finish in time.
+    pub(crate) fn wait_into<W: Write + Send>(
+        &mut self,
+        mut out: W,
+    ) -> Result<(PipelineOutput, u64)> {
+        let stdout = self.running.last_mut().and_then(|r| r.stdout.take());
+        let copy = move |out: &mut W| match stdout {
+            Some(mut stdout) => stdout.copy_to(out),
+            None => Ok(0),
+        };
+
+        let (copy, timed_out) = match self.deadline {
+            Some(deadline) => thread::scope(|scope| {
+                let copying = scope.spawn(|| copy(&mut out));
+                let timed_out = timeout::enforce(self, deadline);
+                match copying.join() {
+                    Ok(copied) => (copied, timed_out),
+                    Err(panic) => panic::resume_unwind(panic),
+                }
+            }),
+            None => (copy(&mut out), Ok(false)),
+        };
+        let stages = self.wait_running();
+        let fed = self.join_feeder();
+        let copied = copy?;
+        fed?;
+        let fired = self.watchdog.as_ref().is_some_and(Watchdog::fired);
+
+        let output = PipelineOutput {
+            stages: stages?,
+            stdout: Vec::new(),
+        };
+        match (timed_out? || fired, self.timeout) {
+            (true, Some(timeout)) => Err(PipeError::TimedOut { timeout, output }),
+            _ => self.policy.check(output).map(|output| (output, copied)),
+        }
+    }
+
+    /// Wait on every running command, returning how each of them
+    /// finished or the first error hit while waiting.
+    fn wait_running(&mut self) -> Result<Vec<StageOutput>> {
+        self.disarm();
+        let mut stages = Vec::new();
+        let mut error = None;
+        for running in self.running.drain(..) {
+            let (status, stderr) = match running.process {
+                Process::Child(mut child) => {
+                    let status = child.wait();
+                    match running.stderr.map(|capture| capture.join()) {
+                        Some(Ok(stderr)) => (status, stderr),
+                        Some(Err(panic)) => panic::resume_unwind(panic),
+                        None => (status, Ok(Vec::new())),
+                    }
+                }
+                Process::Worker(worker) => {
+                    let (status, message) = worker.wait();
+                    (Ok(status), Ok(message))
+                }
+            };
+
+            match (status, stderr) {
+                (Ok(status), Ok(stderr)) => stages.push(StageOutput {
+                    command: running.command,
+                    status,
+                    stderr,
+                }),
+                (Err(e), _) | (_, Err(e)) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(PipeError::Io(e)),
+            None => Ok(stages),
+        }
+    }
+
+    /// Wait for the input of the pipe to be written, if it has any.
+    fn join_feeder(&mut self) -> Result<()> {
+        match self.feeder.take().map(|feeder| feeder.join()) {
+            Some(Ok(fed)) => fed.map_err(PipeError::Io),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+
+    /// Kill and reap every running command.
+    pub(crate) fn kill_running(&mut self) {
+        self.disarm();
+        let _ = self.kill();
+        // Any closure could be blocked writing into its own stdout, or
+        // into a closure after it that is, so close them all up front
+        for running in &mut self.running {
+            running.stdout = None;
+        }
+        for running in self.running.drain(..) {
+            match running.process {
+                Process::Child(mut child) => {
+                    let _ = child.wait();
+                }
+                Process::Worker(worker) => worker.join(),
+            }
+            if let Some(capture) = running.stderr {
+                let _ = capture.join();
+            }
+        }
+    }
+
+    /// Stop waiting on every command, leaving them running.
+    pub(crate) fn detach(&mut self) {
+        self.on_drop = OnDrop::Detach;
+        self.disarm();
+    }
+}
+
+impl Read for RunningPipeline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.running.last_mut().and_then(|r| r.stdout.as_mut()) {
+            Some(stdout) => stdout.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for RunningPipeline {
+    fn drop(&mut self) {
+        match self.on_drop {
+            OnDrop::Kill => self.kill_running(),
+            OnDrop::Wait => {
+                for running in &mut self.running {
+                    running.stdout = None;
+                }
+                let _ = self.wait_running();
+                let _ = self.join_feeder();
+            }
+            OnDrop::Detach => self.disarm(),
+        }
+    }
+}
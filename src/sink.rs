@@ -0,0 +1,16 @@
+use std::io::{self, Write};
+
+/// A writer handing every chunk written to it to a callback,
+/// for `Pipe::finally_to_fn`.
+pub struct Chunks<F>(pub F);
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> Write for Chunks<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use crate::function::Stop;
+use crate::running::RunningPipeline;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long commands are given to exit after being sent `SIGTERM`
+/// before they're killed, unless the pipe says otherwise.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(2);
+
+/// How often running commands are checked on while there's a deadline.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait for every command in `pipeline` to exit before `deadline`.
+/// Once it passes, the ones still running are sent `SIGTERM`, and
+/// any left after the grace period are killed. Returns whether the
+/// deadline passed.
+pub fn enforce(pipeline: &mut RunningPipeline, deadline: Instant) -> io::Result<bool> {
+    if wait_until(pipeline, deadline)? {
+        return Ok(false);
+    }
+
+    pipeline.signal(Signal::SIGTERM)?;
+    let grace = Instant::now() + pipeline.grace;
+    if !wait_until(pipeline, grace)? {
+        pipeline.signal(Signal::SIGKILL)?;
+    }
+    Ok(true)
+}
+
+/// Wait until every stage has finished, returning `false` if
+/// `until` passes first.
+fn wait_until(pipeline: &mut RunningPipeline, until: Instant) -> io::Result<bool> {
+    loop {
+        if pipeline.finished()? {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= until {
+            return Ok(false);
+        }
+        thread::sleep(cmp::min(POLL_INTERVAL, until - now));
+    }
+}
+
+/// A thread that stops the commands of a pipe once its deadline
+/// passes, for while the stdout of the final command is being read
+/// rather than the commands waited on.
+///
+/// It can't check which commands already exited without reaping them,
+/// so it signals every one of them. That's only safe while none have
+/// been reaped, so it has to be disarmed before any are.
+pub struct Watchdog {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// What the watchdog thread shares with the pipeline.
+pub struct State {
+    armed: bool,
+    fired: bool,
+}
+
+/// Every stage of a pipe the watchdog stops.
+pub struct Stages {
+    /// The process group the commands run in, if they have their own.
+    pub group: Option<Pid>,
+    /// The process ID of every command.
+    pub ids: Vec<u32>,
+    /// Every stage running a closure.
+    pub workers: Vec<Stop>,
+}
+
+impl Stages {
+    /// Send `signal` to every command, and stop every closure stage
+    /// if it's `SIGTERM` or `SIGKILL`. The commands can't have been
+    /// reaped yet, but may have exited.
+    pub fn signal(&self, signal: Signal) -> io::Result<()> {
+        if signal == Signal::SIGTERM || signal == Signal::SIGKILL {
+            for worker in &self.workers {
+                worker.stop(signal);
+            }
+        }
+        match self.group {
+            Some(group) => signal::killpg(group, signal)?,
+            None => {
+                for &id in &self.ids {
+                    signal::kill(Pid::from_raw(id as i32), signal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Watchdog {
+    /// Start watching `stages`, which are sent `SIGTERM` once `deadline`
+    /// passes and killed once `grace` has passed after that.
+    pub fn start(stages: Stages, deadline: Instant, grace: Duration) -> Watchdog {
+        let state = Arc::new((
+            Mutex::new(State {
+                armed: true,
+                fired: false,
+            }),
+            Condvar::new(),
+        ));
+        let shared = Arc::clone(&state);
+        thread::spawn(move || {
+            let (ref lock, ref disarmed) = *shared;
+            let state = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut state = match sleep_armed(state, disarmed, deadline) {
+                Some(state) => state,
+                None => return,
+            };
+            state.fired = true;
+            let _ = stages.signal(Signal::SIGTERM);
+            if let Some(_state) = sleep_armed(state, disarmed, Instant::now() + grace) {
+                let _ = stages.signal(Signal::SIGKILL);
+            }
+        });
+        Watchdog { state }
+    }
+
+    /// Return the lock on the watchdog if it's still armed, which keeps
+    /// it from signalling the commands until it's released.
+    pub fn armed(&self) -> Option<MutexGuard<'_, State>> {
+        let state = self.lock();
+        if state.armed {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    /// Keep the watchdog from ever signalling the commands again,
+    /// which has to happen before any of them is reaped.
+    pub fn disarm(&self) {
+        self.lock().armed = false;
+        self.state.1.notify_all();
+    }
+
+    /// Return whether the deadline passed while the watchdog was armed.
+    pub fn fired(&self) -> bool {
+        self.lock().fired
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Sleep until `until` passes, returning `None` as soon as the
+/// watchdog is disarmed. Otherwise the lock is returned still held.
+fn sleep_armed<'a>(
+    mut state: MutexGuard<'a, State>,
+    disarmed: &Condvar,
+    until: Instant,
+) -> Option<MutexGuard<'a, State>> {
+    loop {
+        if !state.armed {
+            return None;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return Some(state);
+        }
+        state = disarmed
+            .wait_timeout(state, until - now)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+}